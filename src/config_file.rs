@@ -0,0 +1,75 @@
+use serde_json::Value;
+use std::path::Path;
+
+/// Name of the (optional) config file in the root of the workspace.
+pub const FILENAME: &str = ".gitlab-lsp.toml";
+
+/// Loads the config file from the workspace at `root`, returning `None` if
+/// there isn't one.
+pub fn load(root: &Path) -> Option<Result<Value, String>> {
+    let contents = std::fs::read_to_string(root.join(FILENAME)).ok()?;
+    Some(parse(&contents).map_err(|err| format!("{FILENAME}: {err}")))
+}
+
+/// Parses the config file, returning its settings in the same form as the
+/// client's initialization options.
+pub fn parse(contents: &str) -> Result<Value, String> {
+    let table = contents.parse::<toml::Table>().map_err(|err| {
+        let line = err
+            .span()
+            .map_or(1, |span| contents[..span.start].matches('\n').count() + 1);
+        format!("line {line}: {}", err.message().replace('\n', "; "))
+    })?;
+    serde_json::to_value(table).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_config() {
+        let contents = textwrap::dedent(
+            r#"
+            # the project
+            project = "group/project" # trailing comment
+            instance_url = 'https://gitlab.example.com'
+            log_level = "debug"
+            resources = ["contacts"]
+            enabled = true
+            limit = 1_000
+
+            [table]
+            key = "value with # and \" in it"
+            "#,
+        );
+
+        assert_eq!(
+            parse(&contents),
+            Ok(json!({
+                "project": "group/project",
+                "instance_url": "https://gitlab.example.com",
+                "log_level": "debug",
+                "resources": ["contacts"],
+                "enabled": true,
+                "limit": 1000,
+                "table": {
+                    "key": "value with # and \" in it",
+                },
+            }))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_config() {
+        assert_eq!(
+            parse("project"),
+            Err("line 1: expected `.`, `=`".to_string())
+        );
+        assert_eq!(
+            parse("log_level = \"debug\"\nproject = group/project"),
+            Err("line 2: invalid string; expected `\"`, `'`".to_string())
+        );
+    }
+}
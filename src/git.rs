@@ -0,0 +1,312 @@
+use std::path::Path;
+
+/// Returns the URL of the named remote (eg `origin`) of the git repo at `root`,
+/// as configured in `.git/config`.
+pub fn remote_url(root: &Path, remote: &str) -> Option<String> {
+    let config = std::fs::read_to_string(root.join(".git").join("config")).ok()?;
+    remote_url_from_config(&config, remote)
+}
+
+fn remote_url_from_config(config: &str, remote: &str) -> Option<String> {
+    let section = format!(r#"[remote "{remote}"]"#);
+    config
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != section)
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .find_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key.trim() == "url").then(|| value.trim().to_string())
+        })
+}
+
+/// Returns the name of the remote which the `glab` CLI has resolved as the
+/// repo's base project (eg via `glab repo set-default`), if any.
+pub fn glab_remote(root: &Path) -> Option<String> {
+    let config = std::fs::read_to_string(root.join(".git").join("config")).ok()?;
+    glab_remote_from_config(&config)
+}
+
+fn glab_remote_from_config(config: &str) -> Option<String> {
+    let mut remote = None;
+    for line in config.lines().map(str::trim) {
+        if line.starts_with('[') {
+            remote = line
+                .strip_prefix(r#"[remote ""#)
+                .and_then(|line| line.strip_suffix(r#""]"#));
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if key.trim() == "glab-resolved" && value.trim() == "base" {
+            if let Some(remote) = remote {
+                return Some(remote.to_string());
+            }
+        }
+    }
+    None
+}
+
+/// Returns the host (eg `gitlab.example.com`) set in the repo's local `glab`
+/// CLI config, in `.git/glab-cli/config.yml`, if any.
+pub fn glab_host(root: &Path) -> Option<String> {
+    let config =
+        std::fs::read_to_string(root.join(".git").join("glab-cli").join("config.yml")).ok()?;
+    glab_host_from_config(&config)
+}
+
+fn glab_host_from_config(config: &str) -> Option<String> {
+    // only top level keys, not those of eg `hosts:`
+    config.lines().find_map(|line| {
+        let value = line.strip_prefix("host:")?.trim();
+        let value = value.trim_matches(|c| c == '"' || c == '\'');
+        (!value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// Returns the path of the GitLab project of the git repo at `root`, from the
+/// remote that the `glab` CLI resolved as its base project (eg the upstream of
+/// a fork) or otherwise `origin`, along with its host: the one set in the
+/// `glab` config if the project came from it, or otherwise the remote's.
+pub fn project_and_host(root: &Path) -> Option<(String, Option<String>)> {
+    match glab_remote(root) {
+        Some(remote) => {
+            let url = remote_url(root, &remote)?;
+            let host = glab_host(root).or_else(|| host_from_remote_url(&url));
+            Some((project_from_remote_url(&url)?, host))
+        }
+        None => {
+            let url = remote_url(root, "origin")?;
+            Some((project_from_remote_url(&url)?, host_from_remote_url(&url)))
+        }
+    }
+}
+
+/// Returns the host of the GitLab instance that a git remote URL points to, eg
+/// `gitlab.example.com`, or for HTTP(S) URLs, its scheme and host, eg
+/// `http://gitlab.example.com:8080`. The port of SSH URLs is SSH's, so isn't
+/// included.
+pub fn host_from_remote_url(url: &str) -> Option<String> {
+    let host = match url.split_once("://") {
+        Some((scheme @ ("http" | "https"), rest)) => {
+            let host = rest.split('/').next()?;
+            let host = host.rsplit_once('@').map_or(host, |(_user, host)| host);
+            return (!host.is_empty()).then(|| format!("{scheme}://{host}"));
+        }
+        // eg ssh://git@gitlab.com:2222/group/project.git
+        Some((_scheme, rest)) => rest.split('/').next()?,
+        // eg git@gitlab.com:group/project.git
+        None => url.split_once(':')?.0,
+    };
+    let host = host.rsplit_once('@').map_or(host, |(_user, host)| host);
+    let host = host.split(':').next()?;
+    (!host.is_empty()).then(|| host.to_string())
+}
+
+/// Returns the path of the GitLab project (eg `group/subgroup/project`) that a
+/// git remote URL points to. Supports both SSH (`git@host:path.git` and
+/// `ssh://git@host/path.git`) and HTTP(S) URLs.
+pub fn project_from_remote_url(url: &str) -> Option<String> {
+    let path = match url.split_once("://") {
+        // eg https://gitlab.com/group/project.git or
+        // ssh://git@gitlab.com:2222/group/project.git
+        Some((_scheme, rest)) => rest.split_once('/')?.1,
+        // eg git@gitlab.com:group/project.git
+        None => url.split_once(':')?.1,
+    };
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+
+    if path.is_empty() {
+        None
+    } else {
+        Some(path.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_url_is_read_from_config() {
+        let config = textwrap::dedent(
+            r#"
+            [core]
+                bare = false
+            [remote "upstream"]
+                url = git@gitlab.com:upstream/project.git
+            [remote "origin"]
+                url = git@gitlab.com:group/subgroup/project.git
+                fetch = +refs/heads/*:refs/remotes/origin/*
+            [branch "main"]
+                remote = origin
+            "#,
+        );
+
+        assert_eq!(
+            remote_url_from_config(&config, "origin"),
+            Some("git@gitlab.com:group/subgroup/project.git".to_string())
+        );
+        assert_eq!(remote_url_from_config(&config, "missing"), None);
+    }
+
+    #[test]
+    fn glab_remote_is_read_from_config() {
+        let config = textwrap::dedent(
+            r#"
+            [remote "origin"]
+                url = git@gitlab.com:fork/project.git
+            [remote "upstream"]
+                url = git@gitlab.com:group/project.git
+                glab-resolved = base
+            "#,
+        );
+
+        assert_eq!(
+            glab_remote_from_config(&config),
+            Some("upstream".to_string())
+        );
+        assert_eq!(glab_remote_from_config("[core]\n\tbare = false"), None);
+    }
+
+    #[test]
+    fn glab_host_is_only_used_for_its_project() {
+        let root = std::env::temp_dir().join(format!("gitlab-lsp-git-{}", std::process::id()));
+        let glab = root.join(".git").join("glab-cli");
+        std::fs::create_dir_all(&glab).unwrap_or_else(|err| panic!("{err}"));
+        let write = |path: std::path::PathBuf, contents: &str| {
+            std::fs::write(path, textwrap::dedent(contents)).unwrap_or_else(|err| panic!("{err}"))
+        };
+        write(glab.join("config.yml"), "host: gitlab.example.com\n");
+
+        write(
+            root.join(".git").join("config"),
+            r#"
+            [remote "origin"]
+                url = git@gitlab.com:fork/project.git
+            "#,
+        );
+        assert_eq!(
+            project_and_host(&root),
+            Some(("fork/project".to_string(), Some("gitlab.com".to_string())))
+        );
+
+        write(
+            root.join(".git").join("config"),
+            r#"
+            [remote "origin"]
+                url = git@gitlab.com:fork/project.git
+            [remote "upstream"]
+                url = git@gitlab.example.com:upstream/project.git
+                glab-resolved = base
+            "#,
+        );
+        assert_eq!(
+            project_and_host(&root),
+            Some((
+                "upstream/project".to_string(),
+                Some("gitlab.example.com".to_string())
+            ))
+        );
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn host_of_self_hosted_origin() {
+        let root = std::env::temp_dir().join(format!("gitlab-lsp-origin-{}", std::process::id()));
+        std::fs::create_dir_all(root.join(".git")).unwrap_or_else(|err| panic!("{err}"));
+        std::fs::write(
+            root.join(".git").join("config"),
+            textwrap::dedent(
+                r#"
+                [remote "origin"]
+                    url = git@gitlab.example.com:group/project.git
+                "#,
+            ),
+        )
+        .unwrap_or_else(|err| panic!("{err}"));
+
+        assert_eq!(
+            project_and_host(&root),
+            Some((
+                "group/project".to_string(),
+                Some("gitlab.example.com".to_string())
+            ))
+        );
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn host_from_remote_urls() {
+        for (url, host) in [
+            (
+                "git@gitlab.example.com:group/project.git",
+                "gitlab.example.com",
+            ),
+            (
+                "ssh://git@gitlab.example.com:2222/group/project.git",
+                "gitlab.example.com",
+            ),
+            (
+                "https://gitlab.example.com/group/project.git",
+                "https://gitlab.example.com",
+            ),
+            (
+                "http://user@gitlab.example.com:8080/group/project",
+                "http://gitlab.example.com:8080",
+            ),
+        ] {
+            assert_eq!(host_from_remote_url(url), Some(host.to_string()), "{url}");
+        }
+        assert_eq!(host_from_remote_url("/some/local/path"), None);
+    }
+
+    #[test]
+    fn glab_host_is_read_from_config() {
+        let config = textwrap::dedent(
+            r#"
+            # glab local config
+            git_protocol: ssh
+            host: gitlab.example.com
+            hosts:
+                gitlab.com:
+                    api_protocol: https
+            "#,
+        );
+
+        assert_eq!(
+            glab_host_from_config(&config),
+            Some("gitlab.example.com".to_string())
+        );
+        assert_eq!(glab_host_from_config("host: \"\""), None);
+    }
+
+    #[test]
+    fn project_from_nested_subgroup_remote_urls() {
+        for url in [
+            "git@gitlab.com:group/subgroup/project.git",
+            "ssh://git@gitlab.com/group/subgroup/project.git",
+            "ssh://git@gitlab.example.com:2222/group/subgroup/project.git",
+            "https://gitlab.com/group/subgroup/project.git",
+            "https://gitlab.com/group/subgroup/project",
+            "https://gitlab.com/group/subgroup/project/",
+        ] {
+            assert_eq!(
+                project_from_remote_url(url),
+                Some("group/subgroup/project".to_string()),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn project_from_invalid_remote_urls() {
+        assert_eq!(project_from_remote_url("https://gitlab.com"), None);
+        assert_eq!(project_from_remote_url("/some/local/path"), None);
+    }
+}
@@ -1,527 +1,8230 @@
-use serde_json::Value;
+use crate::{config_file, git};
+use serde_json::{json, Value};
 use std::collections::{HashMap, HashSet};
-use tokio::sync::Mutex;
+use std::path::PathBuf;
+use tokio::sync::{Mutex, MutexGuard};
 use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer};
-use tower_lsp::{LspService, Server};
+use tower_lsp::{ClientSocket, LspService, Server};
 
 pub async fn run_server() {
     let (stdin, stdout) = (tokio::io::stdin(), tokio::io::stdout());
-    let (service, socket) = LspService::new(|client| Lsp {
-        client,
-        state: Mutex::new(LspState {
-            config: Config {
-                api_key: None,
-                project: None,
-            },
-            sources: HashMap::new(),
-
-            members: HashSet::new(),
-            labels: HashSet::new(),
-            milestones: HashSet::new(),
-        }),
-    });
+    let (service, socket) = build_service();
     Server::new(stdin, stdout, socket).serve(service).await;
 }
 
+/// Builds the service, with its custom methods, apart from any transport, so
+/// that it can also be driven in-process, eg by tests.
+pub fn build_service() -> (LspService<Lsp>, ClientSocket) {
+    LspService::build(Lsp::new)
+        .custom_method("gitlab/ping", Lsp::ping)
+        .finish()
+}
+
+#[derive(Default)]
 pub struct LspState {
     pub config: Config,
+    /// The options supplied by the client, which take precedence over those in
+    /// the config file.
+    pub client_options: Value,
+    pub client_support: ClientSupport,
+    pub workspace_root: Option<PathBuf>,
+    /// The project and instance which were detected at `initialize`, eg from
+    /// the git remote, rather than configured, so that they still apply when
+    /// the config is rebuilt.
+    detected_project: Option<String>,
+    detected_instance_url: Option<String>,
 
     // see https://github.com/ebkalderon/nix-language-server/blob/master/src/backend.rs#L14-L23
     /// Mapping of path names to file contents.
     pub sources: HashMap<String, String>,
+    /// The tier of the GitLab instance, as configured or detected, or `None`
+    /// if it's unknown, in which case all features are offered.
+    tier: Option<Tier>,
+    /// The major and minor version of the GitLab instance, or `None` if it's
+    /// unknown, in which case all quick actions are offered.
+    version: Option<(u32, u32)>,
+    /// Resources which have been fetched since the config last changed.
+    loaded: HashSet<Resource>,
+    /// Lazy resources which are being fetched, so that concurrent completions
+    /// don't fetch them again.
+    loading: HashSet<Resource>,
+    /// The user whose token is used, completed as `me` for quick actions which
+    /// accept it, eg `/assign me`, or `None` if unknown.
+    current_user: Option<CompletionItemData>,
+    /// Whether the project has Service Desk enabled, which is only checked
+    /// if contacts are enabled, as its quick actions suggest their emails.
+    service_desk: bool,
+    /// Whether none of the resources could be fetched when they were last
+    /// fetched, eg because GitLab is unreachable while offline.
+    unreachable: bool,
+    /// Completions of large resources which were recently matched against
+    /// what had been typed.
+    completion_cache: std::sync::Mutex<CompletionCache>,
 
+    boards: HashSet<CompletionItemData>,
+    branches: HashSet<CompletionItemData>,
+    ci_variables: HashSet<CompletionItemData>,
+    contacts: HashSet<CompletionItemData>,
+    epics: HashSet<CompletionItemData>,
+    groups: HashSet<CompletionItemData>,
+    issues: HashSet<CompletionItemData>,
     labels: HashSet<CompletionItemData>,
+    /// The labels' details beyond their completions, by their names.
+    label_data: HashMap<String, LabelData>,
     members: HashSet<CompletionItemData>,
+    /// The members' details beyond their completions, by their usernames.
+    member_data: HashMap<String, MemberData>,
+    /// The name of the project's default branch, which is offered first.
+    default_branch: Option<String>,
+    /// How many of the project's recently updated issues and merge requests
+    /// use each label and milestone, by their kind and name.
+    recent_uses: HashMap<(Resource, String), usize>,
     milestones: HashSet<CompletionItemData>,
+    projects: HashSet<CompletionItemData>,
 }
 
-#[derive(Debug)]
-pub struct Config {
-    pub api_key: Option<String>,
-    pub project: Option<String>,
-}
+impl LspState {
+    /// Replaces the completions of a kind of resource with those fetched.
+    fn set_resource(&mut self, resource_kind: Resource, values: HashSet<CompletionItemData>) {
+        match resource_kind {
+            Resource::Boards => {
+                self.boards = values;
+            }
+            Resource::Branches => {
+                self.branches = values;
+            }
+            Resource::CiVariables => {
+                self.ci_variables = values;
+            }
+            Resource::Contacts => {
+                self.contacts = values;
+            }
+            Resource::Epics => {
+                self.epics = values;
+            }
+            Resource::Groups => {
+                self.groups = values;
+            }
+            Resource::Issues => {
+                self.issues = values;
+            }
+            Resource::Labels => {
+                self.labels = values;
+            }
+            Resource::Members => {
+                self.members = values;
+            }
+            Resource::Milestones => {
+                self.milestones = values;
+            }
+            Resource::Projects => {
+                self.projects = values;
+            }
+            Resource::DueDates
+            | Resource::Emoji
+            | Resource::HealthStatuses
+            | Resource::QuickActions
+            | Resource::Severities => unreachable!(),
+        }
+        self.loaded.insert(resource_kind);
+        self.clear_completion_cache();
+    }
 
-#[derive(Clone, Eq, Hash, PartialEq)]
-struct CompletionItemData {
-    completion: String,
-    description: Option<String>,
+    /// Replaces the completions (and any details) of the kinds of resources
+    /// which were fetched.
+    fn set_fetched(&mut self, fetched: Fetched) {
+        for (resource_kind, values) in fetched.resources {
+            if resource_kind == Resource::Branches {
+                self.default_branch = fetched.default_branch.clone();
+            }
+            self.set_resource(resource_kind, values);
+        }
+        if let Some(label_data) = fetched.label_data {
+            self.label_data = label_data;
+        }
+        if let Some(member_data) = fetched.member_data {
+            self.member_data = member_data;
+        }
+        if let Some(recent_uses) = fetched.recent_uses {
+            self.recent_uses = recent_uses;
+        }
+    }
+
+    /// Replaces everything which was fetched by `Lsp::fetch_resources`.
+    fn set_refetched(&mut self, refetched: Refetched) {
+        self.tier = refetched.tier;
+        self.version = refetched.version;
+        self.current_user = refetched.current_user;
+        self.service_desk = refetched.service_desk;
+        self.unreachable = refetched.unreachable;
+        // lazy resources will be refetched with the new config when next used
+        self.loaded.clear();
+        self.loading.clear();
+        self.set_fetched(refetched.fetched);
+    }
+
+    /// Builds the config afresh from the config file (if any) and then the
+    /// client's `options`, which take precedence, so that settings removed
+    /// from either no longer apply. The token, and anything detected rather
+    /// than configured, are kept.
+    fn rebuild_config(&self, options: &Value) -> Result<Config> {
+        let mut config = Config::default();
+        if let Some(ref root) = self.workspace_root {
+            match config_file::load(root) {
+                Some(Ok(opts)) => config.update(&opts)?,
+                Some(Err(err)) => return Err(invalid_config_file_error(&err)),
+                None => {}
+            }
+        }
+        config.update(options)?;
+
+        config.api_key = self.config.api_key.clone();
+        if config.project.is_none() {
+            config.project = self.detected_project.clone();
+        }
+        if config.instance_url.is_none() {
+            config.instance_url = self.detected_instance_url.clone();
+        }
+        Ok(config)
+    }
+
+    /// Returns the documents whose diagnostics are cleared when the config
+    /// changes to `config`, as they'd no longer be kept up to date, eg after
+    /// `diagnose_quick_actions` is turned off.
+    fn undiagnosed_documents(&self, config: &Config) -> Vec<Url> {
+        if self.config.diagnose_quick_actions && !config.diagnose_quick_actions {
+            self.sources
+                .keys()
+                .filter_map(|key| document_uri(key))
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    fn clear_completion_cache(&self) {
+        if let Ok(mut cache) = self.completion_cache.lock() {
+            cache.entries.clear();
+        }
+    }
+
+    fn resource(&self, resource_kind: Resource) -> &HashSet<CompletionItemData> {
+        match resource_kind {
+            Resource::Boards => &self.boards,
+            Resource::Branches => &self.branches,
+            Resource::CiVariables => &self.ci_variables,
+            Resource::Contacts => &self.contacts,
+            Resource::Epics => &self.epics,
+            Resource::Groups => &self.groups,
+            Resource::Issues => &self.issues,
+            Resource::Labels => &self.labels,
+            Resource::Members => &self.members,
+            Resource::Milestones => &self.milestones,
+            Resource::Projects => &self.projects,
+            Resource::DueDates
+            | Resource::Emoji
+            | Resource::HealthStatuses
+            | Resource::QuickActions
+            | Resource::Severities => unreachable!(),
+        }
+    }
+
+    /// Returns those of `resource_kinds` which are lazy and yet to be fetched,
+    /// marking them as being fetched.
+    fn start_loading(&mut self, resource_kinds: &[Resource]) -> Vec<Resource> {
+        let pending: Vec<Resource> = resource_kinds
+            .iter()
+            .copied()
+            .filter(|resource_kind| {
+                self.config.is_lazy(*resource_kind)
+                    && self.config.is_enabled(*resource_kind)
+                    && !self.loaded.contains(resource_kind)
+                    && !self.loading.contains(resource_kind)
+            })
+            .collect();
+        self.loading.extend(pending.iter().copied());
+        pending
+    }
 }
 
-enum Resource {
-    Labels,
-    Members,
-    Milestones,
-    QuickActions,
+/// The most completions which are cached at once, see `CompletionCache`.
+const COMPLETION_CACHE_SIZE: usize = 16;
+
+/// How long cached completions are reused for, see `CompletionCache`.
+const COMPLETION_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Recent completions of large resources, matched and sorted but not yet
+/// truncated, so that retyping the same thing doesn't match them all again.
+/// Cleared when resources or the config change.
+#[derive(Debug, Default)]
+struct CompletionCache {
+    /// The least recently used first.
+    entries: std::collections::VecDeque<CacheEntry>,
 }
 
-pub struct Lsp {
-    pub client: Client,
-    pub state: Mutex<LspState>,
+/// Completions cached by `CompletionCache`.
+#[derive(Debug)]
+struct CacheEntry {
+    key: CompletionCacheKey,
+    cached_at: std::time::Instant,
+    completions: Vec<(Resource, CompletionItemData)>,
 }
 
-macro_rules! log {
-    // log!(self, LEVEL, "format {args} and {}", such)
-    // where level is LOG, INFO, WARNING, ERROR
-    ($self:ident, $lvl:ident, $($arg:tt)+) => ({
-        $self.client
-            .log_message(MessageType::$lvl, format!($($arg)+))
-            .await;
-    });
+/// The kind of resource completed, and the line being completed (along with
+/// the cursor's index in it), which determine what's offered, eg labels which
+/// are already applied by a quick action aren't.
+type CompletionCacheKey = (Resource, String, usize);
 
-    // log!(self, "format {args} and {}", such)
-    ($self:ident, $($arg:tt)+) => ({
-        $self.client
-            .log_message(MessageType::LOG, format!($($arg)+))
-            .await;
-    });
+impl CompletionCache {
+    fn get(&mut self, key: &CompletionCacheKey) -> Option<Vec<(Resource, CompletionItemData)>> {
+        let now = std::time::Instant::now();
+        self.entries
+            .retain(|entry| now.duration_since(entry.cached_at) < COMPLETION_CACHE_TTL);
+        let index = self.entries.iter().position(|entry| entry.key == *key)?;
+        let entry = self.entries.remove(index)?;
+        let completions = entry.completions.clone();
+        self.entries.push_back(entry);
+        Some(completions)
+    }
+
+    fn insert(
+        &mut self,
+        key: CompletionCacheKey,
+        completions: Vec<(Resource, CompletionItemData)>,
+    ) {
+        if self.entries.len() >= COMPLETION_CACHE_SIZE {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(CacheEntry {
+            key,
+            cached_at: std::time::Instant::now(),
+            completions,
+        });
+    }
 }
 
-macro_rules! log_debug {
-    // log!(self, LEVEL, "format {args} and {}", such)
-    // where level is LOG, INFO, WARNING, ERROR
-    ($self:ident, $lvl:ident, $($arg:tt)+) => ({
-        #[cfg(debug_assertions)]
-        $self.client
-            .log_message(MessageType::$lvl, format!($($arg)+))
-            .await;
-    });
+/// Features supported by the client, as advertised at `initialize`.
+#[derive(Debug, Default)]
+pub struct ClientSupport {
+    /// Whether completions may be snippets, with placeholders.
+    pub snippets: bool,
+    /// Whether completion documentation may be Markdown, rather than plain
+    /// text.
+    pub markdown_documentation: bool,
+}
 
-    // log!(self, "format {args} and {}", such)
-    ($self:ident, $($arg:tt)+) => ({
-        #[cfg(debug_assertions)]
-        $self.client
-            .log_message(MessageType::LOG, format!($($arg)+))
-            .await;
-    });
+impl ClientSupport {
+    fn from_capabilities(capabilities: &ClientCapabilities) -> Self {
+        let completion_item = capabilities
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.completion.as_ref())
+            .and_then(|completion| completion.completion_item.as_ref());
+
+        ClientSupport {
+            snippets: completion_item
+                .and_then(|item| item.snippet_support)
+                .unwrap_or(false),
+            markdown_documentation: completion_item
+                .and_then(|item| item.documentation_format.as_ref())
+                .is_some_and(|formats| formats.contains(&MarkupKind::Markdown)),
+        }
+    }
 }
 
-#[tower_lsp::async_trait]
-impl LanguageServer for Lsp {
-    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
-        log!(
-            self,
-            "[initialize] initializing {} {}",
-            env!("CARGO_PKG_NAME"),
-            env!("CARGO_PKG_VERSION")
-        );
-        log_debug!(self, "[initialize] {params:?}");
+/// The most completions to respond with at once, unless configured otherwise,
+/// so that clients aren't sent thousands of items for huge projects.
+const DEFAULT_MAX_COMPLETION_ITEMS: usize = 200;
 
-        let mut state = self.state.lock().await;
+/// The most requests to send to GitLab at once, unless configured otherwise,
+/// so that fetching many pages of many resources doesn't overwhelm it.
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 8;
 
-        match std::env::var_os("GITLAB_API_PRIVATE_TOKEN") {
-            Some(token) => state.config.api_key = Some(token.to_string_lossy().to_string()),
-            None => {
-                return Err(Error {
-                    code: ErrorCode::ServerError(1),
-                    message: "Error: no GITLAB_API_PRIVATE_TOKEN environment variable detected"
-                        .into(),
-                    data: None,
-                })
-            }
-        };
+/// Advanced settings of the HTTP client used to fetch resources, for tuning
+/// performance, eg on slow links. `None` leaves reqwest's defaults.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HttpOptions {
+    /// Whether to only use HTTP/2, multiplexing requests over one connection,
+    /// without first negotiating it. Only for instances known to support it.
+    pub http2_prior_knowledge: bool,
+    /// The most idle connections to keep open to the instance.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How often to send TCP keepalives on open connections, in seconds.
+    pub tcp_keepalive_secs: Option<u64>,
+}
 
-        if let Some(ref opts) = params.initialization_options {
-            match opts.get("project") {
-                Some(Value::String(project)) => {
-                    state.config.project = Some(project.clone());
+impl HttpOptions {
+    /// Parses the `http` table of the client's config.
+    fn parse(opts: &serde_json::Map<String, Value>) -> Result<Self> {
+        let mut http = HttpOptions::default();
+        for (name, value) in opts {
+            match (name.as_str(), value) {
+                ("http2_prior_knowledge", Value::Bool(enabled)) => {
+                    http.http2_prior_knowledge = *enabled;
                 }
-                Some(_) => {
-                    return Err(Error {
-                        code: ErrorCode::ServerError(1),
-                        message:
-                            "Error: invalid configuration param 'project' supplied, expected string"
-                                .into(),
-                        data: None,
-                    })
+                ("pool_max_idle_per_host", value) => match value.as_u64() {
+                    Some(max_idle) => http.pool_max_idle_per_host = Some(max_idle as usize),
+                    None => {
+                        return Err(invalid_param_error(
+                            "http.pool_max_idle_per_host",
+                            "non-negative integer",
+                        ))
+                    }
+                },
+                ("tcp_keepalive_secs", value) => match value.as_u64() {
+                    Some(secs) if secs > 0 => http.tcp_keepalive_secs = Some(secs),
+                    _ => {
+                        return Err(invalid_param_error(
+                            "http.tcp_keepalive_secs",
+                            "positive integer",
+                        ))
+                    }
+                },
+                ("http2_prior_knowledge", _) => {
+                    return Err(invalid_param_error("http.http2_prior_knowledge", "boolean"))
                 }
-                None => {
-                    return Err(Error {
-                        code: ErrorCode::ServerError(1),
-                        message: "Error: required configuration param 'project' not supplied"
-                            .into(),
-                        data: None,
-                    })
+                _ => {
+                    return Err(invalid_param_error(
+                        "http",
+                        "a table of http2_prior_knowledge, pool_max_idle_per_host or tcp_keepalive_secs",
+                    ))
                 }
             }
         }
-        // log_debug!(self, "[initialize:config] {:#?}", state.config);
+        Ok(http)
+    }
+}
 
-        let api_base = "https://gitlab.com/api/v4";
-        let project = state.config.project.clone().unwrap();
-        let api_key = state.config.api_key.clone().unwrap();
-        let verbose = true;
-        let client = reqwest::ClientBuilder::new()
-            .connection_verbose(verbose)
-            .build()
-            .expect("TODO");
-
-        let requests = vec![
-            make_request(&client, api_base, &api_key, &project, Resource::Labels),
-            make_request(&client, api_base, &api_key, &project, Resource::Milestones),
-            make_request(&client, api_base, &api_key, &project, Resource::Members),
-        ];
-        let responses = futures::future::join_all(requests).await;
-        for res in responses {
-            match res {
-                Ok((resource_kind, Value::Array(json))) => {
-                    let values = process_resource(&resource_kind, json);
-                    match resource_kind {
-                        Resource::Labels => {
-                            state.labels = values;
-                        }
-                        Resource::Members => {
-                            state.members = values;
-                        }
-                        Resource::Milestones => {
-                            state.milestones = values;
-                        }
-                        Resource::QuickActions => unreachable!(),
-                    }
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub api_key: Option<String>,
+    /// The path of a PEM encoded certificate to trust, in addition to the
+    /// system's, eg the CA of a self-hosted instance.
+    pub ca_cert_path: Option<String>,
+    /// Whether to skip verifying the instance's TLS certificate, which is
+    /// insecure, so is only for testing.
+    pub danger_accept_invalid_certs: bool,
+    /// Whether to report lines which look like quick actions, but aren't known,
+    /// eg `/asign`. Off by default, as instances may have their own.
+    pub diagnose_quick_actions: bool,
+    /// What's being written, or `None` to guess from each document's
+    /// pathname, offering quick actions for new issues and merge requests.
+    pub document_context: Option<DocumentContext>,
+    pub http: HttpOptions,
+    /// The URL of the GitLab instance, eg `https://gitlab.example.com`, or
+    /// the full base URL of its API, eg `https://example.com/gitlab/api/v4`.
+    /// Defaults to `https://gitlab.com`.
+    pub instance_url: Option<String>,
+    /// Patterns of labels to offer as completions, or empty to offer all of
+    /// them. See `label_matches`.
+    pub label_include: Vec<String>,
+    /// Patterns of labels to never offer as completions, eg `bot::*`.
+    pub label_exclude: Vec<String>,
+    pub labels_from: LabelsFrom,
+    pub log_level: LogLevel,
+    /// The most completions to respond with at once. Defaults to
+    /// `DEFAULT_MAX_COMPLETION_ITEMS`.
+    pub max_completion_items: Option<usize>,
+    /// The most requests to send to GitLab at once. Defaults to
+    /// `DEFAULT_MAX_CONCURRENT_REQUESTS`.
+    pub max_concurrent_requests: Option<usize>,
+    pub members_scope: MembersScope,
+    /// How many characters of a reference must be typed (after its prefix, eg
+    /// `@`) before fetched resources are completed, eg so that thousands of
+    /// members aren't offered after just `@`. Defaults to 0.
+    pub min_chars_before_complete: usize,
+    /// Whether to offer the labels and milestones used most by the project's
+    /// recently updated issues and merge requests first.
+    pub rank_by_recency: bool,
+    /// Whether to sort members alphabetically, rather than by their access
+    /// level (eg maintainers first) and then alphabetically.
+    pub members_sort_alphabetical: bool,
+    pub project: Option<String>,
+    /// A shell command which prints the API token, as an alternative to
+    /// setting it in the environment.
+    pub token_command: Option<String>,
+    /// Warn when the API token expires within this many days, or don't check
+    /// its expiry if `None`.
+    pub token_expiry_warning_days: Option<u32>,
+    /// The tier of the GitLab instance, or `None` to detect it.
+    pub tier: Option<Tier>,
+    /// The offset from UTC of the user's time zone, in which dates (eg those
+    /// completed for `/due`) are given, or `None` for the server's local time.
+    pub utc_offset: Option<chrono::FixedOffset>,
+    /// Optional resources which have been enabled, in addition to those which
+    /// are always fetched.
+    resources: HashSet<Resource>,
+    /// Kinds of resources which shouldn't have a space inserted after them
+    /// when completed.
+    without_trailing_space: HashSet<Resource>,
+    /// Kinds of resources which are only fetched when first completed, rather
+    /// than at startup.
+    lazy: HashSet<Resource>,
+    /// The kind of completion (which determines its icon in most editors) for
+    /// kinds of resources, instead of their defaults.
+    completion_kinds: HashMap<Resource, CompletionItemKind>,
+    /// Whether to complete bot members, which are excluded by default.
+    include_bots: bool,
+}
+
+impl Config {
+    /// Updates the config from the options supplied by the client, either at
+    /// `initialize` or via `workspace/didChangeConfiguration`.
+    fn update(&mut self, opts: &Value) -> Result<()> {
+        match opts.get("project") {
+            Some(Value::String(project)) => {
+                let project = normalize_project(&expand_env_param("project", project)?);
+                // rather than fetching from, eg, `/projects//labels`
+                if project.is_empty() {
+                    return Err(missing_project_error());
                 }
+                self.project = Some(project);
+            }
+            Some(_) => return Err(invalid_param_error("project", "string")),
+            None => {}
+        }
 
-                Ok((_, _json)) => log!(
-                    self,
-                    ERROR,
-                    "Received unexpected or invalid JSON from Gitlab API."
-                ),
-                Err(err) => log!(self, ERROR, "Received response error: {err}"),
+        match opts.get("ca_cert_path") {
+            Some(Value::String(path)) => {
+                // report a missing or invalid certificate up front, rather than
+                // when fetching resources
+                load_certificate(path)?;
+                self.ca_cert_path = Some(path.clone());
             }
+            Some(_) => return Err(invalid_param_error("ca_cert_path", "string")),
+            None => {}
         }
 
-        Ok(InitializeResult {
-            server_info: None,
-            capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
-                )),
-                completion_provider: Some(CompletionOptions {
-                    resolve_provider: Some(false),
-                    trigger_characters: Some(vec![
-                        "/".to_string(),
-                        "@".to_string(),
-                        "%".to_string(),
-                        "~".to_string(),
-                    ]),
-                    work_done_progress_options: Default::default(),
-                    all_commit_characters: None,
-                    completion_item: None,
-                }),
-                execute_command_provider: None,
-                workspace: None,
-                // workspace: Some(WorkspaceServerCapabilities {
-                //     workspace_folders: Some(WorkspaceFoldersServerCapabilities {
-                //         supported: Some(true),
-                //         change_notifications: Some(OneOf::Left(true)),
-                //     }),
-                //     file_operations: None,
-                // }),
-                document_formatting_provider: None,
-                // TODO go to defn of issue/MR, etc
-                definition_provider: None,
-                ..ServerCapabilities::default()
-            },
-        })
-    }
+        match opts.get("danger_accept_invalid_certs") {
+            Some(Value::Bool(accept)) => {
+                self.danger_accept_invalid_certs = *accept;
+            }
+            Some(_) => {
+                return Err(invalid_param_error(
+                    "danger_accept_invalid_certs",
+                    "boolean",
+                ))
+            }
+            None => {}
+        }
 
-    async fn initialized(&self, _params: InitializedParams) {
-        log_debug!(self, "[initialized] {_params:?}");
-    }
+        match opts.get("diagnose_quick_actions") {
+            Some(Value::Bool(diagnose)) => {
+                self.diagnose_quick_actions = *diagnose;
+            }
+            Some(_) => return Err(invalid_param_error("diagnose_quick_actions", "boolean")),
+            None => {}
+        }
 
-    async fn shutdown(&self) -> Result<()> {
-        log_debug!(self, "[shutdown]");
-        Ok(())
-    }
+        match opts.get("http") {
+            Some(Value::Object(http)) => self.http = HttpOptions::parse(http)?,
+            Some(_) => return Err(invalid_param_error("http", "table")),
+            None => {}
+        }
 
-    async fn did_change_workspace_folders(&self, _params: DidChangeWorkspaceFoldersParams) {
-        log_debug!(self, "[did_change_workspace_folders] {_params:?}");
-    }
+        match opts.get("instance_url") {
+            Some(Value::String(instance_url)) => {
+                self.instance_url = Some(expand_env_param("instance_url", instance_url)?);
+            }
+            Some(_) => return Err(invalid_param_error("instance_url", "string")),
+            None => {}
+        }
 
-    async fn did_change_configuration(&self, _params: DidChangeConfigurationParams) {
-        log_debug!(self, "[did_change_configuration] {_params:?}");
-    }
+        for (name, patterns) in [
+            ("label_include", &mut self.label_include),
+            ("label_exclude", &mut self.label_exclude),
+        ] {
+            match opts.get(name).map(string_array) {
+                Some(Some(values)) => {
+                    *patterns = values;
+                }
+                Some(None) => return Err(invalid_param_error(name, "array of strings")),
+                None => {}
+            }
+        }
 
-    async fn did_change_watched_files(&self, _params: DidChangeWatchedFilesParams) {
-        log_debug!(self, "[did_change_watched_files] {_params:?}");
-    }
+        match opts
+            .get("document_context")
+            .map(|context| context.as_str().and_then(DocumentContext::parse))
+        {
+            Some(Some(context)) => {
+                self.document_context = Some(context);
+            }
+            Some(None) => {
+                return Err(invalid_param_error(
+                    "document_context",
+                    "one of 'issue', 'merge_request', 'comment', 'issue_comment' or 'merge_request_comment'",
+                ))
+            }
+            None => {}
+        }
 
-    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        match opts
+            .get("log_level")
+            .map(|level| level.as_str().and_then(LogLevel::parse))
         {
-            let mut p = params.clone();
-            p.text_document.text = "...trimmed...".to_string();
-            log_debug!(self, "[did_open] {p:?}");
+            Some(Some(level)) => {
+                self.log_level = level;
+            }
+            Some(None) => {
+                return Err(invalid_param_error(
+                    "log_level",
+                    "one of 'error', 'warning', 'info', 'log' or 'debug'",
+                ))
+            }
+            None => {}
         }
 
-        let mut state = self.state.lock().await;
-        state.sources.insert(
-            params.text_document.uri.path().to_owned(),
-            params.text_document.text.clone(),
-        );
-    }
+        match opts.get("max_completion_items").map(Value::as_u64) {
+            Some(Some(max_items)) if max_items > 0 => {
+                self.max_completion_items = Some(max_items as usize);
+            }
+            Some(_) => {
+                return Err(invalid_param_error(
+                    "max_completion_items",
+                    "positive integer",
+                ))
+            }
+            None => {}
+        }
 
-    async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        {
-            let mut p = params.clone();
-            p.content_changes = p
-                .content_changes
-                .into_iter()
-                .map(|mut c| {
-                    c.text = "...trimmed...".to_string();
-                    c
-                })
-                .collect();
-            log_debug!(self, "[did_change] {p:?}");
+        match opts.get("min_chars_before_complete").map(Value::as_u64) {
+            Some(Some(min_chars)) => {
+                self.min_chars_before_complete = min_chars as usize;
+            }
+            Some(None) => {
+                return Err(invalid_param_error(
+                    "min_chars_before_complete",
+                    "non-negative integer",
+                ))
+            }
+            None => {}
         }
 
-        let mut state = self.state.lock().await;
-        let content = match params.content_changes.first() {
-            Some(content) => content.text.clone(),
-            None => String::new(),
-        };
-        state
-            .sources
-            .insert(params.text_document.uri.path().to_owned(), content.clone());
-    }
+        match opts.get("max_concurrent_requests").map(Value::as_u64) {
+            Some(Some(max_requests)) if max_requests > 0 => {
+                self.max_concurrent_requests = Some(max_requests as usize);
+            }
+            Some(_) => {
+                return Err(invalid_param_error(
+                    "max_concurrent_requests",
+                    "positive integer",
+                ))
+            }
+            None => {}
+        }
 
-    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        match opts
+            .get("labels_from")
+            .map(|labels_from| labels_from.as_str().and_then(LabelsFrom::parse))
         {
-            let mut p = params.clone();
-            p.text = Some("...trimmed...".to_string());
-            log_debug!(self, "[did_save] {p:?}");
+            Some(Some(labels_from)) => {
+                self.labels_from = labels_from;
+            }
+            Some(None) => return Err(invalid_param_error("labels_from", "one of 'all' or 'used'")),
+            None => {}
         }
-    }
-
-    async fn did_close(&self, _params: DidCloseTextDocumentParams) {
-        log_debug!(self, "[did_close] {_params:?}");
-    }
 
-    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
-        log_debug!(self, "[completion] {params:?}");
+        match opts.get("rank_by_recency") {
+            Some(Value::Bool(rank)) => {
+                self.rank_by_recency = *rank;
+            }
+            Some(_) => return Err(invalid_param_error("rank_by_recency", "boolean")),
+            None => {}
+        }
 
-        // let contents = contents_of_path(params.text_document_position.text_document.uri.path());
-        let state = self.state.lock().await;
-        let pathname = params.text_document_position.text_document.uri.path();
-        let contents = match state.sources.get(pathname) {
-            Some(contents) => contents.clone(),
-            None => return Ok(None),
-        };
+        match opts
+            .get("members_scope")
+            .map(|scope| scope.as_str().and_then(MembersScope::parse))
+        {
+            Some(Some(scope)) => {
+                self.members_scope = scope;
+            }
+            Some(None) => {
+                return Err(invalid_param_error(
+                    "members_scope",
+                    "one of 'all' or 'direct'",
+                ))
+            }
+            None => {}
+        }
 
-        // dbg!(params.text_document_position.position);
+        match opts.get("token_command") {
+            Some(Value::String(command)) => {
+                self.token_command = Some(command.clone());
+            }
+            Some(_) => return Err(invalid_param_error("token_command", "string")),
+            None => {}
+        }
 
-        let line = contents
-            .lines()
-            .nth(params.text_document_position.position.line as usize)
-            .expect("line (row) should exist");
-        let index = params
-            .text_document_position
-            .position
-            .character
-            .saturating_sub(1) as usize;
+        match opts.get("members_sort") {
+            Some(Value::String(sort)) if sort == "access_level" => {
+                self.members_sort_alphabetical = false;
+            }
+            Some(Value::String(sort)) if sort == "alphabetical" => {
+                self.members_sort_alphabetical = true;
+            }
+            Some(_) => {
+                return Err(invalid_param_error(
+                    "members_sort",
+                    "one of 'access_level' or 'alphabetical'",
+                ))
+            }
+            None => {}
+        }
+
+        match opts.get("exclude_bots") {
+            Some(Value::Bool(exclude_bots)) => {
+                self.include_bots = !exclude_bots;
+            }
+            Some(_) => return Err(invalid_param_error("exclude_bots", "boolean")),
+            None => {}
+        }
+
+        match opts.get("utc_offset") {
+            Some(Value::String(offset)) => match offset.parse::<chrono::FixedOffset>() {
+                Ok(offset) => self.utc_offset = Some(offset),
+                Err(_) => return Err(invalid_param_error("utc_offset", "offset, eg \"+02:00\"")),
+            },
+            Some(_) => return Err(invalid_param_error("utc_offset", "string")),
+            None => {}
+        }
+
+        match opts.get("token_expiry_warning_days").map(Value::as_u64) {
+            Some(Some(days)) => {
+                self.token_expiry_warning_days = Some(days as u32);
+            }
+            Some(None) => {
+                return Err(invalid_param_error(
+                    "token_expiry_warning_days",
+                    "non-negative integer",
+                ))
+            }
+            None => {}
+        }
+
+        match opts
+            .get("tier")
+            .map(|tier| tier.as_str().and_then(Tier::parse))
+        {
+            Some(Some(tier)) => {
+                self.tier = Some(tier);
+            }
+            Some(None) => {
+                return Err(invalid_param_error(
+                    "tier",
+                    "one of 'free', 'premium' or 'ultimate'",
+                ))
+            }
+            None => {}
+        }
+
+        match opts.get("trailing_space") {
+            Some(Value::Object(kinds)) => {
+                let mut without_trailing_space = self.without_trailing_space.clone();
+                for (kind, enabled) in kinds {
+                    match (Resource::parse(kind), enabled) {
+                        (Some(resource_kind), Value::Bool(true)) => {
+                            without_trailing_space.remove(&resource_kind);
+                        }
+                        (Some(resource_kind), Value::Bool(false)) => {
+                            without_trailing_space.insert(resource_kind);
+                        }
+                        _ => {
+                            return Err(invalid_param_error(
+                                "trailing_space",
+                                "table of resource kinds to booleans",
+                            ))
+                        }
+                    }
+                }
+                self.without_trailing_space = without_trailing_space;
+            }
+            Some(_) => return Err(invalid_param_error("trailing_space", "table")),
+            None => {}
+        }
+
+        match opts.get("completion_kind") {
+            Some(Value::Object(kinds)) => {
+                let mut completion_kinds = self.completion_kinds.clone();
+                for (kind, completion_kind) in kinds {
+                    match (
+                        Resource::parse(kind),
+                        completion_kind
+                            .as_str()
+                            .and_then(parse_completion_item_kind),
+                    ) {
+                        (Some(resource_kind), Some(completion_kind)) => {
+                            completion_kinds.insert(resource_kind, completion_kind);
+                        }
+                        _ => {
+                            return Err(invalid_param_error(
+                                "completion_kind",
+                                "table of resource kinds to completion kinds, eg 'reference'",
+                            ))
+                        }
+                    }
+                }
+                self.completion_kinds = completion_kinds;
+            }
+            Some(_) => return Err(invalid_param_error("completion_kind", "table")),
+            None => {}
+        }
+
+        match opts.get("load") {
+            Some(Value::Object(kinds)) => {
+                let mut lazy = self.lazy.clone();
+                for (kind, load) in kinds {
+                    match (Resource::parse(kind), load.as_str()) {
+                        (Some(resource_kind), Some("eager")) => {
+                            lazy.remove(&resource_kind);
+                        }
+                        (Some(resource_kind), Some("lazy")) => {
+                            lazy.insert(resource_kind);
+                        }
+                        _ => {
+                            return Err(invalid_param_error(
+                                "load",
+                                "table of resource kinds to 'eager' or 'lazy'",
+                            ))
+                        }
+                    }
+                }
+                self.lazy = lazy;
+            }
+            Some(_) => return Err(invalid_param_error("load", "table")),
+            None => {}
+        }
+
+        match opts.get("resources") {
+            Some(Value::Array(resources)) => {
+                let mut enabled = HashSet::new();
+                for resource in resources {
+                    match resource.as_str() {
+                        Some("boards") => {
+                            enabled.insert(Resource::Boards);
+                        }
+                        Some("ci_variables") => {
+                            enabled.insert(Resource::CiVariables);
+                        }
+                        Some("contacts") => {
+                            enabled.insert(Resource::Contacts);
+                        }
+                        Some("epics") => {
+                            enabled.insert(Resource::Epics);
+                        }
+                        Some("groups") => {
+                            enabled.insert(Resource::Groups);
+                        }
+                        Some("projects") => {
+                            enabled.insert(Resource::Projects);
+                        }
+                        _ => {
+                            return Err(invalid_param_error(
+                                "resources",
+                                "array of 'boards', 'ci_variables', 'contacts', 'epics', 'groups' \
+                                or 'projects'",
+                            ))
+                        }
+                    }
+                }
+                self.resources = enabled;
+            }
+            Some(_) => return Err(invalid_param_error("resources", "array")),
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// Whether resources fetched with `other` would be the same as those
+    /// fetched with this config.
+    fn fetches_same_resources(&self, other: &Config) -> bool {
+        self.project == other.project
+            && self.instance_url == other.instance_url
+            && self.ca_cert_path == other.ca_cert_path
+            && self.danger_accept_invalid_certs == other.danger_accept_invalid_certs
+            && self.members_scope == other.members_scope
+            && self.labels_from == other.labels_from
+            && self.rank_by_recency == other.rank_by_recency
+            && self.include_bots == other.include_bots
+            && self.tier == other.tier
+            && self.lazy == other.lazy
+            && self.resources == other.resources
+    }
+
+    /// The base URL of the GitLab REST API.
+    fn api_base(&self) -> String {
+        let instance_url = self
+            .instance_url
+            .as_deref()
+            .unwrap_or("https://gitlab.com")
+            .trim_end_matches('/');
+        // allow the API's path (and version) to be set in full, for unusual
+        // setups
+        if instance_url.contains("/api/") {
+            instance_url.to_string()
+        } else {
+            format!("{instance_url}/api/v4")
+        }
+    }
+
+    /// Builds the client for requests to the GitLab API, trusting the
+    /// configured certificate, if any.
+    fn http_client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::ClientBuilder::new().connection_verbose(true);
+        if let Some(ref path) = self.ca_cert_path {
+            builder = builder.add_root_certificate(load_certificate(path)?);
+        }
+        if self.danger_accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if self.http.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(max_idle) = self.http.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(secs) = self.http.tcp_keepalive_secs {
+            builder = builder.tcp_keepalive(std::time::Duration::from_secs(secs));
+        }
+        builder.build().map_err(|err| Error {
+            code: ErrorCode::ServerError(1),
+            message: format!("Error: unable to build HTTP client: {err}").into(),
+            data: None,
+        })
+    }
+
+    /// The URL of the GitLab instance's web UI, eg `https://gitlab.com`.
+    fn web_url(&self) -> String {
+        let instance_url = self
+            .instance_url
+            .as_deref()
+            .unwrap_or("https://gitlab.com")
+            .trim_end_matches('/');
+        // eg https://example.com/gitlab/api/v4
+        match instance_url.split_once("/api/") {
+            Some((web_url, _)) => web_url.to_string(),
+            None => instance_url.to_string(),
+        }
+    }
+
+    /// Whether the label `name` should be offered as a completion, according to
+    /// `label_include` and `label_exclude`.
+    fn offers_label(&self, name: &str) -> bool {
+        (self.label_include.is_empty()
+            || self
+                .label_include
+                .iter()
+                .any(|pattern| label_matches(pattern, name)))
+            && !self
+                .label_exclude
+                .iter()
+                .any(|pattern| label_matches(pattern, name))
+    }
+
+    /// The kind of completion for a resource of this kind, which most editors
+    /// show as an icon.
+    fn completion_kind(&self, resource_kind: Resource) -> CompletionItemKind {
+        if let Some(completion_kind) = self.completion_kinds.get(&resource_kind) {
+            return *completion_kind;
+        }
+        match resource_kind {
+            Resource::Branches | Resource::Contacts | Resource::Projects => {
+                CompletionItemKind::CONSTANT
+            }
+            Resource::CiVariables => CompletionItemKind::VARIABLE,
+            Resource::Epics | Resource::Groups | Resource::Issues | Resource::Members => {
+                CompletionItemKind::REFERENCE
+            }
+            // boards' lists are completed by their labels
+            Resource::Boards
+            | Resource::Emoji
+            | Resource::HealthStatuses
+            | Resource::Labels
+            | Resource::Severities => CompletionItemKind::ENUM_MEMBER,
+            Resource::DueDates => CompletionItemKind::VALUE,
+            Resource::Milestones => CompletionItemKind::EVENT,
+            Resource::QuickActions => CompletionItemKind::KEYWORD,
+        }
+    }
+
+    /// Whether to insert a space after completing a resource of this kind.
+    fn trailing_space(&self, resource_kind: Resource) -> bool {
+        !self.without_trailing_space.contains(&resource_kind)
+    }
+
+    fn max_completion_items(&self) -> usize {
+        self.max_completion_items
+            .unwrap_or(DEFAULT_MAX_COMPLETION_ITEMS)
+    }
+
+    fn max_concurrent_requests(&self) -> usize {
+        self.max_concurrent_requests
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS)
+    }
+
+    /// Whether to skip bot members, eg `project_123_bot`.
+    fn exclude_bots(&self) -> bool {
+        !self.include_bots
+    }
+
+    /// Whether this kind of resource is only fetched when first completed.
+    fn is_lazy(&self, resource_kind: Resource) -> bool {
+        self.lazy.contains(&resource_kind)
+    }
+
+    fn is_enabled(&self, resource_kind: Resource) -> bool {
+        match resource_kind {
+            Resource::Boards
+            | Resource::CiVariables
+            | Resource::Contacts
+            | Resource::Epics
+            | Resource::Groups
+            | Resource::Projects => self.resources.contains(&resource_kind),
+            Resource::Branches
+            | Resource::DueDates
+            | Resource::Emoji
+            | Resource::HealthStatuses
+            | Resource::Issues
+            | Resource::Labels
+            | Resource::Members
+            | Resource::Milestones
+            | Resource::QuickActions
+            | Resource::Severities => true,
+        }
+    }
+}
+
+/// Which project members to offer as completions.
+///
+/// See: https://docs.gitlab.com/ee/api/members.html
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MembersScope {
+    /// Direct members of the project and those inherited from its groups.
+    #[default]
+    All,
+    /// Only direct members of the project.
+    Direct,
+}
+
+impl MembersScope {
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "all" => Some(MembersScope::All),
+            "direct" => Some(MembersScope::Direct),
+            _ => None,
+        }
+    }
+}
+
+/// Which of the project's labels to offer as completions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LabelsFrom {
+    /// All labels of the project and its groups.
+    #[default]
+    All,
+    /// Only those applied to the project's recently updated issues or merge
+    /// requests, eg when its groups have many labels which it doesn't use.
+    Used,
+}
+
+impl LabelsFrom {
+    fn parse(labels_from: &str) -> Option<Self> {
+        match labels_from {
+            "all" => Some(LabelsFrom::All),
+            "used" => Some(LabelsFrom::Used),
+            _ => None,
+        }
+    }
+}
+
+/// What's being written in documents, which determines the quick actions
+/// offered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DocumentContext {
+    /// The description of a new issue.
+    Issue,
+    /// The description of a new merge request.
+    MergeRequest,
+    /// A comment on an existing issue or merge request.
+    Comment,
+    /// A comment on an existing issue.
+    IssueComment,
+    /// A comment on, or review of, an existing merge request.
+    MergeRequestComment,
+}
+
+impl DocumentContext {
+    fn parse(context: &str) -> Option<Self> {
+        match context {
+            "issue" => Some(DocumentContext::Issue),
+            "merge_request" => Some(DocumentContext::MergeRequest),
+            "comment" => Some(DocumentContext::Comment),
+            "issue_comment" => Some(DocumentContext::IssueComment),
+            "merge_request_comment" => Some(DocumentContext::MergeRequestComment),
+            _ => None,
+        }
+    }
+
+    /// The kind of item that the document describes, if known.
+    fn document_kind(self) -> Option<DocumentKind> {
+        match self {
+            DocumentContext::Issue | DocumentContext::IssueComment => Some(DocumentKind::Issue),
+            DocumentContext::MergeRequest | DocumentContext::MergeRequestComment => {
+                Some(DocumentKind::MergeRequest)
+            }
+            DocumentContext::Comment => None,
+        }
+    }
+
+    /// Whether the document is a comment on an existing item.
+    fn is_comment(self) -> bool {
+        matches!(
+            self,
+            DocumentContext::Comment
+                | DocumentContext::IssueComment
+                | DocumentContext::MergeRequestComment
+        )
+    }
+}
+
+/// The tier (ie subscription) of a GitLab instance, which determines the
+/// features available.
+///
+/// See: https://about.gitlab.com/pricing/feature-comparison/
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Tier {
+    Free,
+    Premium,
+    Ultimate,
+}
+
+impl Tier {
+    fn parse(tier: &str) -> Option<Self> {
+        match tier {
+            "free" => Some(Tier::Free),
+            "premium" => Some(Tier::Premium),
+            "ultimate" => Some(Tier::Ultimate),
+            _ => None,
+        }
+    }
+
+    /// Parses the plan of a license, including the names of legacy plans.
+    ///
+    /// See: https://docs.gitlab.com/ee/api/license.html
+    fn from_plan(plan: &str) -> Option<Self> {
+        match plan {
+            "free" | "default" => Some(Tier::Free),
+            "premium" | "silver" | "starter" | "bronze" => Some(Tier::Premium),
+            "ultimate" | "gold" => Some(Tier::Ultimate),
+            _ => None,
+        }
+    }
+}
+
+/// How verbose the server is when logging to the client. Each level includes
+/// all of the levels before it.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warning,
+    Info,
+    Log,
+    Debug,
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            LogLevel::Debug
+        } else {
+            LogLevel::Log
+        }
+    }
+}
+
+impl LogLevel {
+    fn parse(level: &str) -> Option<Self> {
+        match level {
+            "error" => Some(LogLevel::Error),
+            "warning" => Some(LogLevel::Warning),
+            "info" => Some(LogLevel::Info),
+            "log" => Some(LogLevel::Log),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+
+    /// Whether messages of the given type should be logged at this level.
+    fn allows(&self, message_type: MessageType) -> bool {
+        let level = if message_type == MessageType::ERROR {
+            LogLevel::Error
+        } else if message_type == MessageType::WARNING {
+            LogLevel::Warning
+        } else if message_type == MessageType::INFO {
+            LogLevel::Info
+        } else {
+            LogLevel::Log
+        };
+        level <= *self
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+struct CompletionItemData {
+    /// The text to insert, eg `~bug`. A space may be inserted after it,
+    /// depending on the config.
+    completion: String,
+    description: Option<String>,
+    /// A snippet to insert instead of `completion`, with placeholders for the
+    /// user to fill in.
+    snippet: Option<String>,
+    /// The URL of the resource's page in GitLab, if the API provides it.
+    url: Option<String>,
+}
+
+/// A label's details which aren't needed to complete it, but are for API
+/// operations on it, eg renaming it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct LabelData {
+    /// The label's ID, eg for `PUT /projects/:id/labels/:label_id`.
+    id: u64,
+    /// The label's background color, eg `#d9534f`.
+    color: Option<String>,
+}
+
+/// A member's details which aren't needed to complete them, but are for
+/// ranking them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct MemberData {
+    /// The member's access level, eg 40 for a maintainer.
+    ///
+    /// See: https://docs.gitlab.com/ee/api/members.html#roles
+    access_level: u64,
+}
+
+/// The kinds of resources which were fetched, along with the details of the
+/// labels, members and branches, if they were.
+#[derive(Debug, Default)]
+struct Fetched {
+    resources: Vec<(Resource, HashSet<CompletionItemData>)>,
+    label_data: Option<HashMap<String, LabelData>>,
+    member_data: Option<HashMap<String, MemberData>>,
+    /// The name of the project's default branch, if the branches were
+    /// fetched.
+    default_branch: Option<String>,
+    /// How many of the project's recently updated issues and merge requests
+    /// use each label and milestone, if ranking by `rank_by_recency`.
+    recent_uses: Option<HashMap<(Resource, String), usize>>,
+    /// Whether none of the requests reached GitLab, eg while offline.
+    unreachable: bool,
+}
+
+/// Everything fetched by `Lsp::fetch_resources`: details of the instance and
+/// project, and all resources which aren't lazy.
+#[derive(Debug)]
+struct Refetched {
+    tier: Option<Tier>,
+    version: Option<(u32, u32)>,
+    current_user: Option<CompletionItemData>,
+    service_desk: bool,
+    /// Whether none of the resources could be fetched.
+    unreachable: bool,
+    fetched: Fetched,
+}
+
+/// The kinds of resources which are fetched from GitLab, rather than being
+/// known up front.
+const FETCHED_RESOURCES: [Resource; 11] = [
+    Resource::Boards,
+    Resource::Branches,
+    Resource::CiVariables,
+    Resource::Contacts,
+    Resource::Epics,
+    Resource::Groups,
+    Resource::Issues,
+    Resource::Labels,
+    Resource::Milestones,
+    Resource::Members,
+    Resource::Projects,
+];
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Resource {
+    /// The lists of the project's issue boards, by their labels.
+    Boards,
+    Branches,
+    /// The project's CI/CD variables, which only maintainers may list.
+    CiVariables,
+    Contacts,
+    /// Suggested due dates, computed from today's date rather than fetched.
+    DueDates,
+    /// Emoji to react with, from a fixed list rather than the API.
+    Emoji,
+    Epics,
+    Groups,
+    /// The health status of an issue, from a fixed list rather than the API.
+    HealthStatuses,
+    Issues,
+    Labels,
+    Members,
+    Milestones,
+    Projects,
+    QuickActions,
+    /// The severity of an incident, from a fixed list rather than the API.
+    Severities,
+}
+
+impl Resource {
+    /// The name of this kind of resource, as used in config, eg `labels`.
+    fn name(&self) -> &'static str {
+        match self {
+            Resource::Boards => "boards",
+            Resource::Branches => "branches",
+            Resource::CiVariables => "ci_variables",
+            Resource::Contacts => "contacts",
+            Resource::DueDates => "due_dates",
+            Resource::Emoji => "emoji",
+            Resource::Epics => "epics",
+            Resource::Groups => "groups",
+            Resource::HealthStatuses => "health_statuses",
+            Resource::Issues => "issues",
+            Resource::Labels => "labels",
+            Resource::Members => "members",
+            Resource::Milestones => "milestones",
+            Resource::Projects => "projects",
+            Resource::QuickActions => "quick_actions",
+            Resource::Severities => "severities",
+        }
+    }
+
+    /// Parses the name of a kind of resource, as used in config, eg `labels`.
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "boards" => Some(Resource::Boards),
+            "branches" => Some(Resource::Branches),
+            "ci_variables" => Some(Resource::CiVariables),
+            "contacts" => Some(Resource::Contacts),
+            "due_dates" => Some(Resource::DueDates),
+            "emoji" => Some(Resource::Emoji),
+            "epics" => Some(Resource::Epics),
+            "groups" => Some(Resource::Groups),
+            "health_statuses" => Some(Resource::HealthStatuses),
+            "issues" => Some(Resource::Issues),
+            "labels" => Some(Resource::Labels),
+            "members" => Some(Resource::Members),
+            "milestones" => Some(Resource::Milestones),
+            "projects" => Some(Resource::Projects),
+            "quick_actions" => Some(Resource::QuickActions),
+            "severities" => Some(Resource::Severities),
+            _ => None,
+        }
+    }
+}
+
+/// A GitLab quick action, eg `/assign`.
+///
+/// See: https://docs.gitlab.com/ee/user/project/quick_actions.html
+struct QuickAction {
+    name: &'static str,
+    description: &'static str,
+    /// The kinds of resources this quick action takes as its arguments.
+    arguments: &'static [Resource],
+    /// The kind of document this quick action applies to, or `None` if it
+    /// applies to all of them.
+    context: Option<DocumentKind>,
+    /// A snippet to insert after the quick action, if any, eg with a
+    /// placeholder hinting at the expected argument.
+    snippet: Option<&'static str>,
+    /// Whether this quick action only applies to an existing item, eg
+    /// `/reopen`, and so is only offered when writing a comment.
+    existing_only: bool,
+    /// The lowest tier of GitLab which supports this quick action.
+    tier: Tier,
+}
+
+impl QuickAction {
+    /// Whether this quick action accepts `me` in place of the current user's
+    /// username, eg `/assign me`.
+    fn accepts_me(&self) -> bool {
+        matches!(self.name, "assign")
+    }
+
+    /// Whether this quick action only applies to Service Desk issues, where it
+    /// takes email addresses, rather than contacts' references.
+    fn is_service_desk(&self) -> bool {
+        matches!(self.name, "add_email" | "remove_email")
+    }
+
+    /// The earliest version of GitLab (major and minor) which supports this
+    /// quick action, if it's more recent than most instances.
+    fn min_version(&self) -> Option<(u32, u32)> {
+        match self.name {
+            // See: https://docs.gitlab.com/ee/user/project/quick_actions.html#work-items
+            "add_child" | "set_parent" | "remove_parent" => Some((16, 5)),
+            "add_email" => Some((16, 2)),
+            "remove_email" => Some((16, 6)),
+            // an alias of `/award`
+            "react" => Some((16, 7)),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of GitLab item that a document describes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DocumentKind {
+    /// Epics don't have description templates, so are never detected from a
+    /// pathname, but quick actions which only apply to them are hidden from
+    /// issue and merge request templates.
+    Epic,
+    Issue,
+    MergeRequest,
+}
+
+impl DocumentKind {
+    /// Guesses what kind of item is being described by the document at
+    /// `pathname`, eg from the description templates in `.gitlab/`.
+    ///
+    /// See: https://docs.gitlab.com/ee/user/project/description_templates.html
+    fn for_pathname(pathname: &str) -> Option<Self> {
+        if pathname.contains(".gitlab/merge_request_templates/") || pathname.contains("MERGE_REQ") {
+            Some(DocumentKind::MergeRequest)
+        } else if pathname.contains(".gitlab/issue_templates/") {
+            Some(DocumentKind::Issue)
+        } else {
+            None
+        }
+    }
+}
+
+// these are mostly aimed at creating *new* issues and merge requests, so those
+// which only apply to existing ones (eg /reopen) are only offered in comments
+const QUICK_ACTIONS: &[QuickAction] = &[
+    QuickAction {
+        name: "add_child",
+        description: "Add child items to the work item hierarchy",
+        arguments: &[Resource::Issues],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:#issue}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "add_contacts",
+        description: "Add customer relations contacts",
+        arguments: &[Resource::Contacts],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:[contact:email]}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "add_email",
+        description: "Add email participants to a Service Desk issue",
+        arguments: &[Resource::Contacts],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:email}"),
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "approve",
+        description: "Approve the merge request",
+        arguments: &[],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: None,
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "award",
+        description: "Add an emoji reaction",
+        arguments: &[Resource::Emoji],
+        context: None,
+        snippet: Some("${1::emoji:}"),
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "assign",
+        description: "Assign users",
+        arguments: &[Resource::Members],
+        context: None,
+        snippet: Some("${1:@user}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "blocked_by",
+        description: "Is blocked by other issues",
+        arguments: &[Resource::Issues],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:#issue}"),
+        existing_only: false,
+        tier: Tier::Premium,
+    },
+    QuickAction {
+        name: "blocks",
+        description: "Blocks other issues",
+        arguments: &[Resource::Issues],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:#issue}"),
+        existing_only: false,
+        tier: Tier::Premium,
+    },
+    QuickAction {
+        name: "board_move",
+        description: "Move to a list of the issue board",
+        arguments: &[Resource::Boards],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:~list}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "child_epic",
+        description: "Add a child epic",
+        arguments: &[Resource::Epics],
+        context: Some(DocumentKind::Epic),
+        snippet: Some("${1:&epic}"),
+        existing_only: false,
+        tier: Tier::Premium,
+    },
+    QuickAction {
+        name: "clone",
+        description: "Clone to another project",
+        arguments: &[Resource::Projects, Resource::Issues],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:group/project}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "close",
+        description: "Close",
+        arguments: &[],
+        context: None,
+        snippet: None,
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "create_merge_request",
+        description: "Create a merge request from a new branch",
+        arguments: &[Resource::Branches],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:branch}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "draft",
+        description: "Mark as a draft",
+        arguments: &[],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: None,
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "due",
+        description: "Due on a certain date",
+        arguments: &[Resource::DueDates],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:date}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "duplicate",
+        description: "Close as a duplicate of another issue",
+        arguments: &[Resource::Issues],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:#issue}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "relate",
+        description: "Relates to other issues",
+        arguments: &[Resource::Issues],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:#issue}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "epic",
+        description: "Add to an epic",
+        arguments: &[Resource::Epics],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:&epic}"),
+        existing_only: false,
+        tier: Tier::Premium,
+    },
+    QuickAction {
+        name: "health_status",
+        description: "Set the health status",
+        arguments: &[Resource::HealthStatuses],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:status}"),
+        existing_only: false,
+        tier: Tier::Ultimate,
+    },
+    QuickAction {
+        name: "label",
+        description: "Add labels",
+        arguments: &[Resource::Labels],
+        context: None,
+        snippet: Some("${1:~label}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "merge",
+        description: "Merge when the pipeline succeeds",
+        arguments: &[],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: None,
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "milestone",
+        description: "Add to milestone",
+        arguments: &[Resource::Milestones],
+        context: None,
+        snippet: Some("${1:%milestone}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "promote",
+        description: "Promote to an epic",
+        arguments: &[],
+        context: Some(DocumentKind::Issue),
+        snippet: None,
+        existing_only: true,
+        tier: Tier::Premium,
+    },
+    QuickAction {
+        name: "ready",
+        description: "Mark as ready",
+        arguments: &[],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: None,
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "rebase",
+        description: "Rebase the source branch onto the target branch",
+        arguments: &[],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: None,
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "react",
+        description: "Add an emoji reaction, like `/award`",
+        arguments: &[Resource::Emoji],
+        context: None,
+        snippet: Some("${1::emoji:}"),
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "reassign_reviewer",
+        description: "Replace the reviewers",
+        arguments: &[Resource::Members],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: Some("${1:@user}"),
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "relabel",
+        description: "Replace all labels with these, unlike `/label`, which adds to them",
+        arguments: &[Resource::Labels],
+        context: None,
+        snippet: Some("${1:~label}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "remove_contacts",
+        description: "Remove customer relations contacts",
+        arguments: &[Resource::Contacts],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:[contact:email]}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "remove_email",
+        description: "Remove email participants from a Service Desk issue",
+        arguments: &[Resource::Contacts],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:email}"),
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "remove_parent",
+        description: "Remove the parent item in the work item hierarchy",
+        arguments: &[],
+        context: Some(DocumentKind::Issue),
+        snippet: None,
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "reopen",
+        description: "Reopen",
+        arguments: &[],
+        context: None,
+        snippet: None,
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "request_review",
+        description: "Request a review from users",
+        arguments: &[Resource::Members],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: Some("${1:@user}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "set_parent",
+        description: "Set the parent item in the work item hierarchy",
+        arguments: &[Resource::Issues],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:#issue}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "severity",
+        description: "Set the severity of an incident",
+        arguments: &[Resource::Severities],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:severity}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "shrug",
+        description: "Append the comment with ¯\\_(ツ)_/¯",
+        arguments: &[],
+        context: None,
+        snippet: Some("${1:comment}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "submit_review",
+        description: "Submit a pending review",
+        arguments: &[],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: None,
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "tableflip",
+        description: "Append the comment with (╯°□°)╯︵ ┻━┻",
+        arguments: &[],
+        context: None,
+        snippet: Some("${1:comment}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "target_branch",
+        description: "Set the target branch",
+        arguments: &[Resource::Branches],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: Some("${1:branch}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "timeline",
+        description: "Add an event to the incident's timeline",
+        arguments: &[],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:comment} | ${2:YYYY-MM-DD} ${3:HH:MM}"),
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "title",
+        description: "Set title",
+        arguments: &[],
+        context: None,
+        snippet: Some("${1:title}"),
+        existing_only: false,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "unassign",
+        description: "Remove assignees",
+        arguments: &[Resource::Members],
+        context: None,
+        snippet: Some("${1:@user}"),
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "unapprove",
+        description: "Remove your approval of the merge request",
+        arguments: &[],
+        context: Some(DocumentKind::MergeRequest),
+        snippet: None,
+        existing_only: true,
+        tier: Tier::Free,
+    },
+    QuickAction {
+        name: "weight",
+        description: "Set weight",
+        arguments: &[],
+        context: Some(DocumentKind::Issue),
+        snippet: Some("${1:1}"),
+        existing_only: false,
+        tier: Tier::Premium,
+    },
+];
+
+/// Returns a branch name for the `/title` set in `source`, if any, in the same
+/// form GitLab suggests when creating a merge request from an issue, eg
+/// `/title Fix the thing` becomes `fix-the-thing`.
+fn suggested_branch_name(source: &str) -> Option<String> {
+    let title = source.lines().find_map(|line| {
+        let qa = quick_action_for_line(line)?;
+        (qa.name == "title").then(|| line.trim_start()["/title".len()..].trim())
+    })?;
+    let branch = title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    if branch.is_empty() {
+        None
+    } else {
+        Some(branch)
+    }
+}
+
+impl QuickAction {
+    /// Describes how to use this quick action, eg `/assign @user`.
+    fn usage(&self) -> String {
+        let Some(snippet) = self.snippet else {
+            return format!("/{}", self.name);
+        };
+        // use the placeholders' hints, eg `${1:@user}` becomes `@user`
+        let mut hint = String::new();
+        let mut rest = snippet;
+        while let Some((before, placeholder)) = rest.split_once("${") {
+            let Some((placeholder, after)) = placeholder.split_once('}') else {
+                break;
+            };
+            hint.push_str(before);
+            hint.push_str(
+                placeholder
+                    .split_once(':')
+                    .map_or(placeholder, |(_, hint)| hint),
+            );
+            rest = after;
+        }
+        hint.push_str(rest);
+        format!("/{} {hint}", self.name)
+    }
+}
+
+/// The severities of an incident, with the alias that GitLab also accepts for
+/// each.
+///
+/// See: https://docs.gitlab.com/ee/operations/incident_management/manage_incidents.html#change-severity
+const SEVERITIES: &[(&str, &str)] = &[
+    ("critical", "S1"),
+    ("high", "S2"),
+    ("medium", "S3"),
+    ("low", "S4"),
+];
+
+/// The health statuses of an issue, with their names in the GitLab UI.
+///
+/// See: https://docs.gitlab.com/ee/user/project/issues/managing_issues.html#health-status
+const HEALTH_STATUSES: &[(&str, &str)] = &[
+    ("on_track", "On track"),
+    ("needs_attention", "Needs attention"),
+    ("at_risk", "At risk"),
+];
+
+/// Commonly used predefined CI/CD variables, with their descriptions.
+///
+/// See: https://docs.gitlab.com/ee/ci/variables/predefined_variables.html
+const PREDEFINED_CI_VARIABLES: &[(&str, &str)] = &[
+    ("CI", "Available in all jobs executed in CI/CD, `true` when available"),
+    ("CI_COMMIT_BRANCH", "The commit branch name, except in merge request pipelines and tag pipelines"),
+    ("CI_COMMIT_MESSAGE", "The full commit message"),
+    ("CI_COMMIT_REF_NAME", "The branch or tag name for which the project is built"),
+    ("CI_COMMIT_REF_SLUG", "`CI_COMMIT_REF_NAME` in lowercase, shortened to 63 bytes, for use in URLs and domain names"),
+    ("CI_COMMIT_SHA", "The commit revision the project is built for"),
+    ("CI_COMMIT_SHORT_SHA", "The first eight characters of `CI_COMMIT_SHA`"),
+    ("CI_COMMIT_TAG", "The commit tag name, only in pipelines for tags"),
+    ("CI_DEFAULT_BRANCH", "The name of the project's default branch"),
+    ("CI_ENVIRONMENT_NAME", "The name of the environment for this job"),
+    ("CI_JOB_ID", "The internal ID of the job, unique across all jobs in the instance"),
+    ("CI_JOB_NAME", "The name of the job"),
+    ("CI_JOB_TOKEN", "A token to authenticate with certain API endpoints"),
+    ("CI_MERGE_REQUEST_IID", "The project-level IID of the merge request, in merge request pipelines"),
+    ("CI_MERGE_REQUEST_LABELS", "Comma-separated label names of the merge request, in merge request pipelines"),
+    ("CI_MERGE_REQUEST_SOURCE_BRANCH_NAME", "The source branch name of the merge request, in merge request pipelines"),
+    ("CI_MERGE_REQUEST_TARGET_BRANCH_NAME", "The target branch name of the merge request, in merge request pipelines"),
+    ("CI_PIPELINE_ID", "The instance-level ID of the current pipeline"),
+    ("CI_PIPELINE_SOURCE", "How the pipeline was triggered, eg `push`, `merge_request_event` or `schedule`"),
+    ("CI_PROJECT_DIR", "The full path the repository is cloned to, and where the job runs from"),
+    ("CI_PROJECT_NAME", "The name of the directory for the project"),
+    ("CI_PROJECT_PATH", "The project namespace with the project name included"),
+    ("CI_REGISTRY_IMAGE", "The address of the project's container registry"),
+    ("GITLAB_USER_LOGIN", "The username of the user who started the pipeline"),
+];
+
+/// Emoji commonly awarded as reactions, by their names in GitLab, with the
+/// emoji themselves.
+///
+/// See: https://docs.gitlab.com/ee/user/emoji_reactions.html
+const EMOJI: &[(&str, &str)] = &[
+    ("thumbsup", "👍"),
+    ("thumbsdown", "👎"),
+    ("smile", "😄"),
+    ("laughing", "😆"),
+    ("tada", "🎉"),
+    ("confused", "😕"),
+    ("heart", "❤️"),
+    ("rocket", "🚀"),
+    ("eyes", "👀"),
+    ("clap", "👏"),
+    ("fire", "🔥"),
+    ("100", "💯"),
+    ("thinking", "🤔"),
+    ("pray", "🙏"),
+    ("white_check_mark", "✅"),
+    ("x", "❌"),
+    ("warning", "⚠️"),
+    ("bug", "🐛"),
+];
+
+/// Returns the quick action that `line` starts with, if any.
+fn quick_action_for_line(line: &str) -> Option<&'static QuickAction> {
+    let name = line
+        .trim_start()
+        .strip_prefix('/')?
+        .split_whitespace()
+        .next()?;
+    QUICK_ACTIONS.iter().find(|qa| qa.name == name)
+}
+
+/// Returns the index (in chars) of the quoted reference which is left open at
+/// the end of `line`, if any, eg `~"needs`, whose name continues on the next
+/// line.
+fn open_quoted_reference(line: &str) -> Option<usize> {
+    let chars: Vec<char> = line.chars().collect();
+    // the last quote must open a reference, rather than close one
+    let quote = chars.iter().rposition(|c| *c == '"')?;
+    let start = quote.checked_sub(1)?;
+    let at_boundary = start == 0 || matches!(chars[start - 1], ' ' | '\t');
+    (matches!(chars[start], '~' | '%') && at_boundary).then_some(start)
+}
+
+/// Whether the document at `pathname` is GitLab CI config, which is YAML
+/// rather than Markdown, so quick actions don't apply.
+///
+/// See: https://docs.gitlab.com/ee/ci/yaml/
+fn is_ci_config(pathname: &str) -> bool {
+    let filename = pathname.rsplit('/').next().unwrap_or(pathname);
+    matches!(filename, ".gitlab-ci.yml" | ".gitlab-ci.yaml")
+}
+
+/// Returns a diagnostic for each line of `source` which looks like a quick
+/// action, but isn't one of `QUICK_ACTIONS`, eg a typo like `/asign`.
+fn quick_action_diagnostics(source: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut in_code_block = false;
+    for (line_number, line) in source.lines().enumerate() {
+        let trimmed = line.trim_start();
+        // GitLab doesn't run quick actions in code blocks
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        let Some(rest) = trimmed.strip_prefix('/').filter(|_| !in_code_block) else {
+            continue;
+        };
+        let name = rest.split_whitespace().next().unwrap_or_default();
+        // eg a path, like `/usr/bin`, rather than a quick action
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            continue;
+        }
+        if QUICK_ACTIONS.iter().any(|qa| qa.name == name) {
+            continue;
+        }
+
+        let start = line[..line.len() - trimmed.len()].encode_utf16().count() as u32;
+        diagnostics.push(Diagnostic {
+            range: Range {
+                start: Position {
+                    line: line_number as u32,
+                    character: start,
+                },
+                end: Position {
+                    line: line_number as u32,
+                    character: start + 1 + name.len() as u32,
+                },
+            },
+            severity: Some(DiagnosticSeverity::INFORMATION),
+            source: Some(env!("CARGO_PKG_NAME").to_string()),
+            message: format!("Unknown quick action '/{name}'"),
+            ..Diagnostic::default()
+        });
+    }
+    diagnostics
+}
+
+/// Command to log (and return) everything the server has loaded, to help debug
+/// missing completions.
+const DUMP_STATE_COMMAND: &str = "gitlab.dumpState";
+
+/// Command to show (and return) the web URL of the reference at a position,
+/// given as `TextDocumentPositionParams`, so that editors can copy it.
+const COPY_REFERENCE_URL_COMMAND: &str = "gitlab.copyReferenceUrl";
+
+/// Command to refetch a single kind of resource, given by name (eg `labels`),
+/// rather than everything.
+const REFRESH_RESOURCE_COMMAND: &str = "gitlab.refreshResource";
+
+/// Command to refetch everything, eg once GitLab can be reached again.
+const REFRESH_COMMAND: &str = "gitlab.refresh";
+
+/// Custom notification of how many of each kind of resource have been fetched,
+/// sent after loading or refreshing them, eg for an editor's status bar.
+enum ResourceCounts {}
+
+impl notification::Notification for ResourceCounts {
+    type Params = Value;
+    const METHOD: &'static str = "gitlab/resourceCounts";
+}
+
+/// The server, whose clones share its state, eg to fetch resources in the
+/// background.
+#[derive(Clone)]
+pub struct Lsp {
+    pub client: Client,
+    pub state: std::sync::Arc<Mutex<LspState>>,
+    /// A copy of `config.log_level`, so that we can log while `state` is
+    /// locked.
+    pub log_level: std::sync::Arc<std::sync::RwLock<LogLevel>>,
+    /// When the server was started, to report its uptime.
+    started: std::time::Instant,
+    /// Whether the initial fetch of resources has completed, which is tracked
+    /// outside of `state` so that it can be checked while that's locked.
+    resources_loaded: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// The kinds of resources whose API the user has been warned is
+    /// deprecated, so that they're only warned once.
+    deprecations: std::sync::Arc<std::sync::Mutex<HashSet<Resource>>>,
+}
+
+impl Lsp {
+    pub fn new(client: Client) -> Self {
+        Lsp {
+            client,
+            state: Default::default(),
+            log_level: Default::default(),
+            started: std::time::Instant::now(),
+            resources_loaded: Default::default(),
+            deprecations: Default::default(),
+        }
+    }
+
+    /// Handles the custom `gitlab/ping` request, which editors may use to check
+    /// that the server is alive, and whether it's ready to complete resources.
+    async fn ping(&self) -> Result<Value> {
+        Ok(json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "resources_loaded": self
+                .resources_loaded
+                .load(std::sync::atomic::Ordering::Relaxed),
+        }))
+    }
+
+    fn log_level(&self) -> LogLevel {
+        self.log_level
+            .read()
+            .map_or_else(|_| LogLevel::default(), |level| *level)
+    }
+
+    fn set_log_level(&self, level: LogLevel) {
+        if let Ok(mut log_level) = self.log_level.write() {
+            *log_level = level;
+        }
+    }
+}
+
+macro_rules! log {
+    // log!(self, LEVEL, "format {args} and {}", such)
+    // where level is LOG, INFO, WARNING, ERROR
+    ($self:ident, $lvl:ident, $($arg:tt)+) => ({
+        if $self.log_level().allows(MessageType::$lvl) {
+            $self.client
+                .log_message(MessageType::$lvl, format!($($arg)+))
+                .await;
+        }
+    });
+
+    // log!(self, "format {args} and {}", such)
+    ($self:ident, $($arg:tt)+) => ({
+        log!($self, LOG, $($arg)+)
+    });
+}
+
+macro_rules! log_debug {
+    // log_debug!(self, LEVEL, "format {args} and {}", such)
+    // where level is LOG, INFO, WARNING, ERROR
+    ($self:ident, $lvl:ident, $($arg:tt)+) => ({
+        if $self.log_level() == LogLevel::Debug {
+            $self.client
+                .log_message(MessageType::$lvl, format!($($arg)+))
+                .await;
+        }
+    });
+
+    // log_debug!(self, "format {args} and {}", such)
+    ($self:ident, $($arg:tt)+) => ({
+        log_debug!($self, LOG, $($arg)+)
+    });
+}
+
+impl Lsp {
+    /// Sends the `gitlab/resourceCounts` notification.
+    async fn notify_resource_counts(&self, counts: Value) {
+        log_debug!(self, "[resource_counts] {counts}");
+        self.client
+            .send_notification::<ResourceCounts>(counts)
+            .await;
+    }
+
+    /// Publishes diagnostics for the document at `uri`, if enabled by
+    /// `diagnose_quick_actions`.
+    async fn publish_diagnostics(&self, uri: Url, version: Option<i32>) {
+        let diagnostics = {
+            let state = self.state.lock().await;
+            if !state.config.diagnose_quick_actions {
+                return;
+            }
+            let key = document_key(&uri);
+            match state.sources.get(&key) {
+                Some(source) if !is_ci_config(&key) => quick_action_diagnostics(source),
+                _ => vec![],
+            }
+        };
+        self.client
+            .publish_diagnostics(uri, diagnostics, version)
+            .await;
+    }
+
+    /// Replaces the current config, refetching resources if needed. The
+    /// `state` is released while refetching, so that other requests aren't
+    /// blocked meanwhile.
+    async fn apply_config(&self, mut state: MutexGuard<'_, LspState>, config: Config) {
+        let refetch = !config.fetches_same_resources(&state.config);
+        let undiagnosed = state.undiagnosed_documents(&config);
+        self.set_log_level(config.log_level);
+        state.config = config.clone();
+        state.clear_completion_cache();
+        drop(state);
+
+        for uri in undiagnosed {
+            self.client.publish_diagnostics(uri, vec![], None).await;
+        }
+
+        if !refetch {
+            return;
+        }
+        log!(self, "[apply_config] configuration changed, refetching");
+        let refetched = match self.fetch_resources(&config).await {
+            Ok(refetched) => refetched,
+            Err(err) => {
+                self.show_fetch_error(err).await;
+                return;
+            }
+        };
+        let (counts, unreachable) = {
+            let mut state = self.state.lock().await;
+            // unless they're stale, as the config changed again while fetching
+            if !state.config.fetches_same_resources(&config) {
+                return;
+            }
+            if let Some(refetched) = refetched {
+                state.set_refetched(refetched);
+            }
+            (resource_counts(&state), state.unreachable)
+        };
+        self.notify_resource_counts(counts).await;
+        self.warn_if_unreachable(unreachable).await;
+    }
+
+    /// Warns the user if GitLab couldn't be reached when fetching resources, eg
+    /// while offline, rather than silently offering no completions.
+    async fn warn_if_unreachable(&self, unreachable: bool) {
+        if unreachable {
+            self.client
+                .show_message(
+                    MessageType::WARNING,
+                    format!(
+                        "GitLab unreachable; completions unavailable; run \
+                        {REFRESH_COMMAND} when online"
+                    ),
+                )
+                .await;
+        }
+    }
+
+    /// Warns the user if the API token is about to expire, if enabled by
+    /// `token_expiry_warning_days`, so that completions don't silently stop
+    /// working.
+    ///
+    /// See: https://docs.gitlab.com/ee/api/personal_access_tokens.html#using-a-request-header
+    async fn check_token_expiry(&self) {
+        let (Some(warning_days), Some(api_key), api_base, Ok(client), utc_offset) = ({
+            let state = self.state.lock().await;
+            (
+                state.config.token_expiry_warning_days,
+                state.config.api_key.clone(),
+                state.config.api_base(),
+                state.config.http_client(),
+                state.config.utc_offset,
+            )
+        }) else {
+            return;
+        };
+
+        let res = client
+            .get(format!("{api_base}/personal_access_tokens/self"))
+            .bearer_auth(api_key)
+            .send()
+            .await;
+        let json = match res {
+            Ok(res) => res.json::<Value>().await.unwrap_or(Value::Null),
+            Err(err) => {
+                log!(self, WARNING, "[token_expiry] unable to check token: {err}");
+                return;
+            }
+        };
+        // eg a token which never expires, or isn't a personal access token
+        let Some(expires_at) = json["expires_at"].as_str() else {
+            log_debug!(self, "[token_expiry] no expiry: {json}");
+            return;
+        };
+
+        if let Some(message) = token_expiry_warning(expires_at, today(utc_offset), warning_days) {
+            self.client
+                .show_message(MessageType::WARNING, message)
+                .await;
+        }
+    }
+
+    /// Returns what's needed to make requests to the GitLab API with `config`,
+    /// or `None` if there's no API token, or the HTTP client can't be built.
+    async fn request_context(&self, config: &Config, caller: &str) -> Option<RequestContext> {
+        let api_key = config.api_key.clone()?;
+        let client = match config.http_client() {
+            Ok(client) => client,
+            Err(err) => {
+                log!(self, ERROR, "[{caller}] {}", err.message);
+                return None;
+            }
+        };
+        Some(RequestContext {
+            logger: RequestLogger {
+                client: self.client.clone(),
+                level: self.log_level(),
+                deprecations: self.deprecations.clone(),
+            },
+            // shared by all requests, including those for each page
+            limiter: std::sync::Arc::new(tokio::sync::Semaphore::new(
+                config.max_concurrent_requests(),
+            )),
+            client,
+            api_base: config.api_base(),
+            api_key,
+        })
+    }
+
+    /// Fetches all enabled resources from GitLab with `config`, along with
+    /// details of the instance and project. Lazy resources are fetched when
+    /// first completed instead. The state isn't locked meanwhile, so the
+    /// results are applied by the caller, with `LspState::set_refetched`.
+    ///
+    /// Returns an error if GitLab rejects the API token, or `None` if nothing
+    /// could be fetched with `config`, eg without a token.
+    async fn fetch_resources(&self, config: &Config) -> Result<Option<Refetched>> {
+        let Some(context) = self.request_context(config, "fetch_resources").await else {
+            return Ok(None);
+        };
+        let metadata = fetch_json(&context, "metadata").await;
+        let version = metadata.as_ref().and_then(version_from_metadata);
+        let tier = match config.tier {
+            Some(tier) => Some(tier),
+            None => detect_tier(&context, metadata.as_ref()).await,
+        };
+        log_debug!(
+            self,
+            "[fetch_resources] tier: {tier:?}, version: {version:?}"
+        );
+        let current_user = fetch_json(&context, "user")
+            .await
+            .as_ref()
+            .and_then(current_user_completion);
+        log_debug!(
+            self,
+            "[fetch_resources] current user: {:?}",
+            current_user.as_ref().map(|user| &user.description)
+        );
+        // See: https://docs.gitlab.com/ee/api/projects.html#get-a-single-project
+        let service_desk = match config.project.as_deref() {
+            Some(project) if config.is_enabled(Resource::Contacts) => {
+                let resource = format!("projects/{}", project.replace('/', "%2F"));
+                fetch_json(&context, &resource)
+                    .await
+                    .is_some_and(|project| project["service_desk_enabled"] == Value::Bool(true))
+            }
+            _ => false,
+        };
+
+        let resource_kinds: Vec<Resource> = FETCHED_RESOURCES
+            .into_iter()
+            .filter(|resource_kind| {
+                config.is_enabled(*resource_kind) && !config.is_lazy(*resource_kind)
+            })
+            .collect();
+        let fetched = self.fetch(config, tier, resource_kinds).await?;
+        let unreachable = fetched.unreachable;
+        if unreachable {
+            log!(
+                self,
+                ERROR,
+                "[fetch_resources] unable to reach GitLab for any resources"
+            );
+        }
+
+        Ok(Some(Refetched {
+            tier,
+            version,
+            current_user,
+            service_desk,
+            unreachable,
+            fetched,
+        }))
+    }
+
+    /// Tells the user why resources couldn't be fetched in the background, eg
+    /// after the config changed, rather than silently completing stale ones.
+    async fn show_fetch_error(&self, err: Error) {
+        log!(self, ERROR, "[fetch] {}", err.message);
+        self.client
+            .show_message(MessageType::ERROR, err.message)
+            .await;
+    }
+
+    /// Fetches the `pending` lazy resources with `config`, which have been
+    /// marked as loading (see `LspState::start_loading`).
+    async fn load_lazy_resources(
+        &self,
+        config: Config,
+        tier: Option<Tier>,
+        pending: Vec<Resource>,
+    ) {
+        log_debug!(self, "[load_lazy_resources] fetching {pending:?}");
+        let fetched = self.fetch(&config, tier, pending.clone()).await;
+
+        let counts = {
+            let mut state = self.state.lock().await;
+            for resource_kind in &pending {
+                state.loading.remove(resource_kind);
+            }
+            let fetched = match fetched {
+                Ok(fetched) => fetched,
+                Err(err) => {
+                    drop(state);
+                    self.show_fetch_error(err).await;
+                    return;
+                }
+            };
+            // unless they're stale, as the config changed while fetching
+            if !state.config.fetches_same_resources(&config) {
+                return;
+            }
+            state.set_fetched(fetched);
+            resource_counts(&state)
+        };
+        self.notify_resource_counts(counts).await;
+    }
+
+    /// Fetches the given kinds of resources from GitLab, or returns an error if
+    /// it rejects the API token.
+    async fn fetch(
+        &self,
+        config: &Config,
+        tier: Option<Tier>,
+        resource_kinds: Vec<Resource>,
+    ) -> Result<Fetched> {
+        let Some(project) = config.project.clone() else {
+            return Ok(Fetched::default());
+        };
+        let Some(context) = self.request_context(config, "fetch").await else {
+            return Ok(Fetched::default());
+        };
+        let members_scope = config.members_scope;
+        let exclude_bots = config.exclude_bots();
+        let requests = resource_kinds
+            .into_iter()
+            // epics aren't available on Free instances
+            .filter(|resource_kind| {
+                *resource_kind != Resource::Epics || tier.is_none_or(|tier| tier >= Tier::Premium)
+            })
+            .map(|resource_kind| make_request(&context, &project, members_scope, resource_kind));
+        let responses = futures::future::join_all(requests).await;
+
+        let mut fetched = vec![];
+        let (mut label_data, mut member_data, mut default_branch) = (None, None, None);
+        let mut unauthorized = false;
+        let (requested, mut unreachable) = (responses.len(), 0);
+        for res in responses {
+            match res {
+                Ok((resource_kind, Ok(Value::Array(json)))) => {
+                    match resource_kind {
+                        Resource::Labels => label_data = Some(process_label_data(&json)),
+                        Resource::Members => member_data = Some(process_member_data(&json)),
+                        Resource::Branches => default_branch = process_default_branch(&json),
+                        _ => {}
+                    }
+                    fetched.push((
+                        resource_kind,
+                        process_resource(&resource_kind, json, exclude_bots),
+                    ));
+                }
+
+                // the token is invalid or has expired, which is reported once
+                Ok((_, Err(RequestError::Status(reqwest::StatusCode::UNAUTHORIZED)))) => {
+                    unauthorized = true
+                }
+                Ok((
+                    resource_kind,
+                    Err(RequestError::Status(status @ reqwest::StatusCode::FORBIDDEN)),
+                )) => log!(
+                    self,
+                    ERROR,
+                    "[{}] Received {status} from Gitlab API; the API token may lack the read_api scope.",
+                    resource_kind.name()
+                ),
+                Ok((resource_kind, Err(RequestError::Status(status)))) => log!(
+                    self,
+                    ERROR,
+                    "[{}] Received {status} from Gitlab API.",
+                    resource_kind.name()
+                ),
+                Ok((resource_kind, Ok(_) | Err(RequestError::InvalidJson))) => log!(
+                    self,
+                    ERROR,
+                    "[{}] Received unexpected or invalid JSON from Gitlab API.",
+                    resource_kind.name()
+                ),
+                Ok((resource_kind, Err(RequestError::Unreachable(err)))) => {
+                    unreachable += 1;
+                    log!(
+                        self,
+                        ERROR,
+                        "[{}] Unable to reach Gitlab API: {err}",
+                        resource_kind.name()
+                    )
+                }
+                Err(err) => log!(self, ERROR, "Received response error: {err}"),
+            }
+        }
+        if unauthorized {
+            return Err(token_invalid_error());
+        }
+
+        // labels may be filtered, and labels and milestones ranked, by their use
+        // in the project's recently updated issues and merge requests
+        let filter_labels = config.labels_from == LabelsFrom::Used;
+        let needs_recent = fetched
+            .iter()
+            .any(|(resource_kind, _)| match resource_kind {
+                Resource::Labels => filter_labels || config.rank_by_recency,
+                Resource::Milestones => config.rank_by_recency,
+                _ => false,
+            });
+        let mut uses = None;
+        if needs_recent {
+            // See: https://docs.gitlab.com/ee/api/issues.html#list-project-issues
+            let recent = |items: &str| {
+                let resource = format!(
+                    "projects/{}/{items}?order_by=updated_at&per_page=100",
+                    project.replace('/', "%2F")
+                );
+                let context = &context;
+                async move { fetch_json(context, &resource).await }
+            };
+            match tokio::join!(recent("issues"), recent("merge_requests")) {
+                (Some(Value::Array(issues)), Some(Value::Array(merge_requests))) => {
+                    let used = used_label_names(issues.iter().chain(&merge_requests));
+                    for (resource_kind, items) in fetched.iter_mut() {
+                        if *resource_kind == Resource::Labels && filter_labels {
+                            items.retain(|label| used.contains(&bare_name(&label.completion)));
+                        }
+                    }
+                    if config.rank_by_recency {
+                        uses = Some(recent_uses(issues.iter().chain(&merge_requests)));
+                    }
+                }
+                _ => log!(
+                    self,
+                    WARNING,
+                    "[fetch] unable to find which labels and milestones are used, so offering \
+                    all of them, unranked"
+                ),
+            }
+        }
+
+        Ok(Fetched {
+            resources: fetched,
+            label_data,
+            member_data,
+            default_branch,
+            recent_uses: uses,
+            unreachable: requested > 0 && unreachable == requested,
+        })
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Lsp {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        log!(
+            self,
+            "[initialize] initializing {} {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        );
+        log_debug!(self, "[initialize] {params:?}");
+
+        let mut state = self.state.lock().await;
+
+        state.client_support = ClientSupport::from_capabilities(&params.capabilities);
+        state.workspace_root = workspace_root(&params, std::env::current_dir().ok());
+        log_debug!(
+            self,
+            "[initialize] workspace root: {:?}",
+            state.workspace_root
+        );
+
+        if let Some(ref opts) = params.initialization_options {
+            state.client_options = opts.clone();
+        }
+        state.config = state.rebuild_config(&state.client_options)?;
+        self.set_log_level(state.config.log_level);
+
+        match state.config.token_command.clone() {
+            Some(command) => {
+                log_debug!(self, "[initialize] running token_command: {command}");
+                // the command may take a while, eg to unlock a password
+                // manager, so don't hold the state meanwhile, eg for `ping`
+                drop(state);
+                let token = token_from_command(&command).await?;
+                state = self.state.lock().await;
+                state.config.api_key = Some(token);
+            }
+            // eg the token is already known
+            None if state.config.api_key.is_some() => {}
+            None => match std::env::var_os("GITLAB_API_PRIVATE_TOKEN") {
+                Some(token) => state.config.api_key = Some(token.to_string_lossy().to_string()),
+                None => {
+                    return Err(Error {
+                        code: ErrorCode::ServerError(1),
+                        message: "Error: no GITLAB_API_PRIVATE_TOKEN environment variable detected"
+                            .into(),
+                        data: Some(json!({
+                            "kind": "missing_token",
+                            "env": "GITLAB_API_PRIVATE_TOKEN",
+                        })),
+                    })
+                }
+            },
+        };
+        // log_debug!(self, "[initialize:config] {:#?}", state.config);
+
+        // note what's configured, so that what's detected can be kept when the
+        // config is rebuilt
+        let configured = (
+            state.config.instance_url.is_some(),
+            state.config.project.is_some(),
+        );
+
+        // fall back to the predefined variables when running in GitLab CI; not
+        // `CI_JOB_TOKEN` though, as it can't read most of the resources
+        // See: https://docs.gitlab.com/ee/ci/variables/predefined_variables.html
+        if state.config.instance_url.is_none() {
+            state.config.instance_url = std::env::var("CI_API_V4_URL").ok();
+        }
+        if state.config.project.is_none() {
+            state.config.project = std::env::var("CI_PROJECT_PATH")
+                .ok()
+                .map(|project| normalize_project(&project));
+        }
+
+        // then to the repo's git remote, preferring the one that the glab CLI
+        // resolved as the base project (eg the upstream of a fork)
+        if state.config.project.is_none() {
+            if let Some((project, host)) = state
+                .workspace_root
+                .as_ref()
+                .and_then(|root| git::project_and_host(root))
+            {
+                state.config.project = Some(project);
+                if state.config.instance_url.is_none() {
+                    state.config.instance_url = host.map(|host| {
+                        if host.contains("://") {
+                            host
+                        } else {
+                            format!("https://{host}")
+                        }
+                    });
+                }
+            }
+            log_debug!(
+                self,
+                "[initialize] detected project from git remote: {:?}",
+                state.config.project
+            );
+        }
+
+        if !configured.0 {
+            state.detected_instance_url = state.config.instance_url.clone();
+        }
+        if !configured.1 {
+            state.detected_project = state.config.project.clone();
+        }
+
+        if state.config.project.is_none() {
+            return Err(missing_project_error());
+        }
+
+        // as when the config changes, don't hold the state while fetching
+        let config = state.config.clone();
+        drop(state);
+        if let Some(refetched) = self.fetch_resources(&config).await? {
+            self.state.lock().await.set_refetched(refetched);
+        }
+        self.resources_loaded
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+
+        Ok(InitializeResult {
+            // the same name that editors use to configure the server, eg in
+            // Zed's `lsp` settings
+            server_info: Some(ServerInfo {
+                name: env!("CARGO_PKG_NAME").to_string(),
+                version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            }),
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::INCREMENTAL),
+                        // resync with the saved document, in case any changes
+                        // were missed
+                        save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                            include_text: Some(true),
+                        })),
+                        ..TextDocumentSyncOptions::default()
+                    },
+                )),
+                completion_provider: Some(CompletionOptions {
+                    resolve_provider: Some(false),
+                    trigger_characters: Some(vec![
+                        "#".to_string(),
+                        "&".to_string(),
+                        "/".to_string(),
+                        "[".to_string(),
+                        "@".to_string(),
+                        "%".to_string(),
+                        "~".to_string(),
+                    ]),
+                    work_done_progress_options: Default::default(),
+                    all_commit_characters: None,
+                    completion_item: None,
+                }),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        DUMP_STATE_COMMAND.to_string(),
+                        COPY_REFERENCE_URL_COMMAND.to_string(),
+                        REFRESH_RESOURCE_COMMAND.to_string(),
+                        REFRESH_COMMAND.to_string(),
+                    ],
+                    work_done_progress_options: Default::default(),
+                }),
+                workspace: None,
+                // workspace: Some(WorkspaceServerCapabilities {
+                //     workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                //         supported: Some(true),
+                //         change_notifications: Some(OneOf::Left(true)),
+                //     }),
+                //     file_operations: None,
+                // }),
+                document_formatting_provider: None,
+                // TODO go to defn of issue/MR, etc
+                definition_provider: None,
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                inlay_hint_provider: Some(OneOf::Left(true)),
+                references_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: Default::default(),
+                })),
+                ..ServerCapabilities::default()
+            },
+        })
+    }
+
+    async fn initialized(&self, _params: InitializedParams) {
+        log_debug!(self, "[initialized] {_params:?}");
+
+        // watch the config file, so that we can reload it when it's changed
+        // outside of the editor; only the one in the workspace's root is
+        // loaded, so that's the only one watched
+        let root = self.state.lock().await.workspace_root.clone();
+        if let Some(base_uri) = root.and_then(|root| Url::from_directory_path(root).ok()) {
+            let registration = Registration {
+                id: "gitlab-lsp-config-file".to_string(),
+                method: "workspace/didChangeWatchedFiles".to_string(),
+                register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
+                    watchers: vec![FileSystemWatcher {
+                        glob_pattern: GlobPattern::Relative(RelativePattern {
+                            base_uri: OneOf::Right(base_uri),
+                            pattern: config_file::FILENAME.to_string(),
+                        }),
+                        kind: None,
+                    }],
+                })
+                .ok(),
+            };
+            if let Err(err) = self.client.register_capability(vec![registration]).await {
+                log!(
+                    self,
+                    WARNING,
+                    "[initialized] unable to watch {}: {err}",
+                    config_file::FILENAME
+                );
+            }
+        }
+
+        // resources were loaded during `initialize`, but notifications can't
+        // be sent until now
+        let (counts, unreachable) = {
+            let state = self.state.lock().await;
+            (resource_counts(&state), state.unreachable)
+        };
+        self.notify_resource_counts(counts).await;
+        self.warn_if_unreachable(unreachable).await;
+
+        self.check_token_expiry().await;
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        log_debug!(self, "[shutdown]");
+        Ok(())
+    }
+
+    async fn did_change_workspace_folders(&self, _params: DidChangeWorkspaceFoldersParams) {
+        log_debug!(self, "[did_change_workspace_folders] {_params:?}");
+    }
+
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        log_debug!(self, "[did_change_configuration] {params:?}");
+
+        // settings may or may not be namespaced under our name, depending on
+        // the client
+        let settings = params
+            .settings
+            .get(env!("CARGO_PKG_NAME"))
+            .unwrap_or(&params.settings);
+
+        let mut state = self.state.lock().await;
+        let mut options = state.client_options.clone();
+        match (&mut options, settings) {
+            (Value::Object(options), Value::Object(settings)) => {
+                options.extend(settings.clone());
+            }
+            (options, Value::Object(_)) => {
+                *options = settings.clone();
+            }
+            _ => {}
+        }
+        let config = match state.rebuild_config(&options) {
+            Ok(config) => config,
+            Err(err) => {
+                log!(self, ERROR, "[did_change_configuration] {}", err.message);
+                return;
+            }
+        };
+
+        state.client_options = options;
+        self.apply_config(state, config).await;
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        log_debug!(self, "[did_change_watched_files] {params:?}");
+
+        let state = self.state.lock().await;
+        let Some(ref root) = state.workspace_root else {
+            return;
+        };
+        let path = root.join(config_file::FILENAME);
+        if !params.changes.iter().any(|change| {
+            change
+                .uri
+                .to_file_path()
+                .is_ok_and(|changed| changed == path)
+        }) {
+            return;
+        }
+
+        let config = match state.rebuild_config(&state.client_options) {
+            Ok(config) => config,
+            Err(err) => {
+                log!(self, ERROR, "[did_change_watched_files] {}", err.message);
+                return;
+            }
+        };
+
+        log!(
+            self,
+            "[did_change_watched_files] reloaded {}",
+            config_file::FILENAME
+        );
+        self.apply_config(state, config).await;
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        {
+            let mut p = params.clone();
+            p.text_document.text = "...trimmed...".to_string();
+            log_debug!(self, "[did_open] {p:?}");
+        }
+
+        {
+            let mut state = self.state.lock().await;
+            state.sources.insert(
+                document_key(&params.text_document.uri),
+                params.text_document.text.clone(),
+            );
+        }
+        self.publish_diagnostics(params.text_document.uri, Some(params.text_document.version))
+            .await;
+    }
+
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        {
+            let mut p = params.clone();
+            p.content_changes = p
+                .content_changes
+                .into_iter()
+                .map(|mut c| {
+                    c.text = "...trimmed...".to_string();
+                    c
+                })
+                .collect();
+            log_debug!(self, "[did_change] {p:?}");
+        }
+
+        {
+            let mut state = self.state.lock().await;
+            let source = state
+                .sources
+                .entry(document_key(&params.text_document.uri))
+                .or_default();
+            for change in params.content_changes {
+                match change.range {
+                    Some(range) => apply_change(source, range, &change.text),
+                    None => *source = change.text,
+                }
+            }
+        }
+        self.publish_diagnostics(params.text_document.uri, Some(params.text_document.version))
+            .await;
+    }
+
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        {
+            let mut p = params.clone();
+            p.text = p.text.map(|_| "...trimmed...".to_string());
+            log_debug!(self, "[did_save] {p:?}");
+        }
+
+        if let Some(text) = params.text {
+            let mut state = self.state.lock().await;
+            state
+                .sources
+                .insert(document_key(&params.text_document.uri), text);
+        }
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        log_debug!(self, "[did_close] {params:?}");
+
+        // clear any diagnostics, which are only kept up to date while open
+        let diagnose = self.state.lock().await.config.diagnose_quick_actions;
+        if diagnose {
+            self.client
+                .publish_diagnostics(params.text_document.uri, vec![], None)
+                .await;
+        }
+    }
+
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        log_debug!(self, "[completion] {params:?}");
+
+        // tower-lsp abandons a request which the client cancels (eg because
+        // the user kept typing) at its next await, so give a superseded
+        // request the chance to be dropped before doing any work
+        tokio::task::yield_now().await;
+
+        // don't hold the lock across any awaits (including logging), so that
+        // other requests aren't blocked while we respond
+        let (response, lazy) = {
+            let mut state = self.state.lock().await;
+            let pathname = &document_key(&params.text_document_position.text_document.uri);
+            let position = &params.text_document_position.position;
+            let resource_kinds = resources_for_position(&state, pathname, position);
+            let pending = state.start_loading(&resource_kinds);
+            let mut response = completions_for_position(&state, pathname, position);
+            // until lazy resources are fetched, offer what's already been
+            // fetched, asking the client to request completions again
+            if resource_kinds
+                .iter()
+                .any(|resource_kind| state.loading.contains(resource_kind))
+            {
+                response = Some(CompletionResponse::List(CompletionList {
+                    is_incomplete: true,
+                    items: match response {
+                        Some(CompletionResponse::Array(items)) => items,
+                        Some(CompletionResponse::List(list)) => list.items,
+                        None => vec![],
+                    },
+                }));
+            }
+            let lazy = (!pending.is_empty()).then(|| (state.config.clone(), state.tier, pending));
+            (response, lazy)
+        };
+
+        // fetch lazy resources in the background, rather than keeping the user
+        // waiting for them
+        if let Some((config, tier, pending)) = lazy {
+            let lsp = self.clone();
+            tokio::spawn(async move { lsp.load_lazy_resources(config, tier, pending).await });
+        }
+        log_debug!(
+            self,
+            "[completion] {} items",
+            match response {
+                Some(CompletionResponse::Array(ref items)) => items.len(),
+                Some(CompletionResponse::List(ref list)) => list.items.len(),
+                None => 0,
+            }
+        );
+
+        Ok(response)
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<Value>> {
+        log_debug!(self, "[execute_command] {params:?}");
+
+        if params.command == COPY_REFERENCE_URL_COMMAND {
+            let Some(Ok(position)) = params
+                .arguments
+                .into_iter()
+                .next()
+                .map(serde_json::from_value::<TextDocumentPositionParams>)
+            else {
+                return Err(Error::invalid_params(
+                    "expected the position of a reference as the argument",
+                ));
+            };
+            let url = {
+                let state = self.state.lock().await;
+                reference_url_at_position(
+                    &state,
+                    &document_key(&position.text_document.uri),
+                    &position.position,
+                )
+            };
+            let Some(url) = url else {
+                return Ok(None);
+            };
+            // LSP can't write to the clipboard, so show the URL for the user
+            // (or the editor's extension) to copy
+            self.client.show_message(MessageType::INFO, &url).await;
+            return Ok(Some(Value::String(url)));
+        }
+
+        if params.command == REFRESH_COMMAND {
+            let config = self.state.lock().await.config.clone();
+            let refetched = self.fetch_resources(&config).await?;
+            let (counts, unreachable) = {
+                let mut state = self.state.lock().await;
+                // unless they're stale, as the config changed while fetching
+                if let (Some(refetched), true) =
+                    (refetched, state.config.fetches_same_resources(&config))
+                {
+                    state.set_refetched(refetched);
+                }
+                (resource_counts(&state), state.unreachable)
+            };
+            self.notify_resource_counts(counts.clone()).await;
+            self.warn_if_unreachable(unreachable).await;
+            log!(
+                self,
+                "[execute_command] {REFRESH_COMMAND}: refreshed {counts}"
+            );
+
+            return Ok(Some(counts));
+        }
+
+        if params.command == REFRESH_RESOURCE_COMMAND {
+            let Some(resource_kind) = resource_to_refresh(&params.arguments) else {
+                return Err(Error::invalid_params(
+                    "expected the kind of resource to refresh as the argument, eg \"labels\"",
+                ));
+            };
+            let (config, tier) = {
+                let state = self.state.lock().await;
+                (state.config.clone(), state.tier)
+            };
+            if !config.is_enabled(resource_kind) {
+                return Err(Error::invalid_params(format!(
+                    "{} are not enabled",
+                    resource_kind.name()
+                )));
+            }
+
+            let fetched = self.fetch(&config, tier, vec![resource_kind]).await?;
+            let mut count = None;
+            let counts = {
+                let mut state = self.state.lock().await;
+                // unless they're stale, as the config changed while fetching
+                if state.config.fetches_same_resources(&config) {
+                    count = fetched.resources.first().map(|(_, values)| values.len());
+                    state.set_fetched(fetched);
+                }
+                resource_counts(&state)
+            };
+            self.notify_resource_counts(counts).await;
+            log!(
+                self,
+                "[execute_command] {REFRESH_RESOURCE_COMMAND}: refreshed {}, {} items",
+                resource_kind.name(),
+                count.map_or("no".to_string(), |count| count.to_string())
+            );
+
+            return Ok(Some(json!({
+                "resource": resource_kind.name(),
+                "count": count,
+            })));
+        }
+
+        if params.command != DUMP_STATE_COMMAND {
+            return Err(Error::invalid_params(format!(
+                "unknown command: {}",
+                params.command
+            )));
+        }
+
+        let dump = {
+            let state = self.state.lock().await;
+            dump_state(&state)
+        };
+        log!(
+            self,
+            "[execute_command] {DUMP_STATE_COMMAND}: {}",
+            serde_json::to_string_pretty(&dump).unwrap_or_default()
+        );
+
+        Ok(Some(dump))
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        log_debug!(self, "[hover] {params:?}");
+
+        let state = self.state.lock().await;
+        let pathname = document_key(&params.text_document_position_params.text_document.uri);
+        Ok(hover_for_position(
+            &state,
+            &pathname,
+            &params.text_document_position_params.position,
+        ))
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        log_debug!(self, "[inlay_hint] {params:?}");
+
+        let state = self.state.lock().await;
+        Ok(Some(inlay_hints(
+            &state,
+            &document_key(&params.text_document.uri),
+            &params.range,
+        )))
+    }
+
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        log_debug!(self, "[references] {params:?}");
+
+        let (label, locations) = {
+            let state = self.state.lock().await;
+            let pathname = &document_key(&params.text_document_position.text_document.uri);
+            match label_at_position(&state, pathname, &params.text_document_position.position) {
+                Some(label) => {
+                    let locations = label_locations(&state, &label.name);
+                    (label, locations)
+                }
+                None => return Ok(None),
+            }
+        };
+
+        log_debug!(
+            self,
+            "[references] label: {:?}, {} references",
+            label.name,
+            locations.len()
+        );
+
+        Ok(Some(
+            locations
+                .into_iter()
+                .map(|(uri, range)| Location { uri, range })
+                .collect(),
+        ))
+    }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        log_debug!(self, "[prepare_rename] {params:?}");
+
+        let state = self.state.lock().await;
+        Ok(label_at_position(
+            &state,
+            &document_key(&params.text_document.uri),
+            &params.position,
+        )
+        .map(|label| PrepareRenameResponse::Range(label.range(params.position.line))))
+    }
+
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        log_debug!(self, "[rename] {params:?}");
+
+        let locations = {
+            let state = self.state.lock().await;
+            let pathname = &document_key(&params.text_document_position.text_document.uri);
+            match label_at_position(&state, pathname, &params.text_document_position.position) {
+                Some(label) => label_locations(&state, &label.name),
+                None => return Ok(None),
+            }
+        };
+
+        // allow the new name to be given as either `bug` or `~bug`
+        let new_name = params.new_name.trim();
+        let new_name = new_name.strip_prefix('~').unwrap_or(new_name);
+        let new_name = new_name
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+            .unwrap_or(new_name);
+        if new_name.is_empty() {
+            return Err(Error::invalid_params("label name must not be empty"));
+        }
+        let new_text = if new_name.contains(' ') {
+            format!(r#"~"{new_name}""#)
+        } else {
+            format!("~{new_name}")
+        };
+
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        for (uri, range) in locations {
+            changes.entry(uri).or_default().push(TextEdit {
+                range,
+                new_text: new_text.clone(),
+            });
+        }
+
+        Ok(Some(WorkspaceEdit {
+            changes: Some(changes),
+            ..WorkspaceEdit::default()
+        }))
+    }
+}
+
+/// Counts each enabled kind of resource fetched, or `null` if lazy and unfetched.
+fn resource_counts(state: &LspState) -> Value {
+    Value::Object(
+        FETCHED_RESOURCES
+            .into_iter()
+            .filter(|resource_kind| state.config.is_enabled(*resource_kind))
+            .map(|resource_kind| {
+                let count = state
+                    .loaded
+                    .contains(&resource_kind)
+                    .then(|| state.resource(resource_kind).len());
+                (resource_kind.name().to_string(), json!(count))
+            })
+            .collect(),
+    )
+}
+
+/// Describes the config (with the API token redacted) and all of the resources
+/// which have been fetched.
+fn dump_state(state: &LspState) -> Value {
+    let config = &state.config;
+    let mut resources: Vec<String> = config
+        .resources
+        .iter()
+        .map(|resource_kind| format!("{resource_kind:?}"))
+        .collect();
+    resources.sort();
+
+    let items = |items: &HashSet<CompletionItemData>| {
+        let mut items: Vec<&CompletionItemData> = items.iter().collect();
+        items.sort_by(|a, b| a.completion.cmp(&b.completion));
+        items
+            .into_iter()
+            .map(|item| json!({"completion": item.completion, "description": item.description}))
+            .collect::<Vec<Value>>()
+    };
+
+    let label_data: serde_json::Map<String, Value> = state
+        .label_data
+        .iter()
+        .map(|(name, data)| (name.clone(), json!({"id": data.id, "color": data.color})))
+        .collect();
+
+    json!({
+        "config": {
+            "api_key": config.api_key.as_ref().map(|_| "<redacted>"),
+            "diagnose_quick_actions": config.diagnose_quick_actions,
+            "document_context": config.document_context.map(|context| format!("{context:?}")),
+            "http": {
+                "http2_prior_knowledge": config.http.http2_prior_knowledge,
+                "pool_max_idle_per_host": config.http.pool_max_idle_per_host,
+                "tcp_keepalive_secs": config.http.tcp_keepalive_secs,
+            },
+            "instance_url": config.instance_url,
+            "label_include": config.label_include,
+            "label_exclude": config.label_exclude,
+            "log_level": format!("{:?}", config.log_level),
+            "max_completion_items": config.max_completion_items(),
+            "max_concurrent_requests": config.max_concurrent_requests(),
+            "min_chars_before_complete": config.min_chars_before_complete,
+            "labels_from": format!("{:?}", config.labels_from),
+            "rank_by_recency": config.rank_by_recency,
+            "members_scope": format!("{:?}", config.members_scope),
+            "members_sort_alphabetical": config.members_sort_alphabetical,
+            "exclude_bots": config.exclude_bots(),
+            "project": config.project,
+            "resources": resources,
+            "token_command": config.token_command,
+            "token_expiry_warning_days": config.token_expiry_warning_days,
+            "tier": config.tier.map(|tier| format!("{tier:?}")),
+            "utc_offset": config.utc_offset.map(|offset| offset.to_string()),
+        },
+        "workspace_root": state.workspace_root,
+        "tier": state.tier.map(|tier| format!("{tier:?}")),
+        "version": state.version.map(|(major, minor)| format!("{major}.{minor}")),
+        "loaded": state.loaded.iter().map(Resource::name).collect::<Vec<&str>>(),
+        "boards": items(&state.boards),
+        "branches": items(&state.branches),
+        "ci_variables": items(&state.ci_variables),
+        "contacts": items(&state.contacts),
+        "epics": items(&state.epics),
+        "groups": items(&state.groups),
+        "issues": items(&state.issues),
+        "labels": items(&state.labels),
+        "label_data": label_data,
+        "members": items(&state.members),
+        "milestones": items(&state.milestones),
+        "projects": items(&state.projects),
+    })
+}
+
+/// Parses the kind of resource to refresh from the arguments of
+/// `REFRESH_RESOURCE_COMMAND`, eg `["labels"]`.
+fn resource_to_refresh(arguments: &[Value]) -> Option<Resource> {
+    let resource_kind = arguments.first()?.as_str().and_then(Resource::parse)?;
+    FETCHED_RESOURCES
+        .contains(&resource_kind)
+        .then_some(resource_kind)
+}
+
+/// Returns the web URL of the reference at `position` in the document at
+/// `pathname`, eg `#123` links to issue 123 of the project.
+fn reference_url_at_position(
+    state: &LspState,
+    pathname: &str,
+    position: &Position,
+) -> Option<String> {
+    let line = state
+        .sources
+        .get(pathname)?
+        .lines()
+        .nth(position.line as usize)?;
+    let (prefix, reference) = ['#', '!', '&', '@', '%', '~']
+        .into_iter()
+        .find_map(|prefix| {
+            find_references(line, prefix)
+                .into_iter()
+                .find(|r| r.start <= position.character && position.character <= r.end)
+                .map(|reference| (prefix, reference.name))
+        })?;
+
+    reference_url(&state.config, prefix, &reference)
+}
+
+/// Returns the web URL of the reference to `reference` with `prefix`, eg `#`
+/// and `123` for issue 123 of the project.
+fn reference_url(config: &Config, prefix: char, reference: &str) -> Option<String> {
+    let web_url = config.web_url();
+    let project = config.project.as_deref()?;
+    let group = project.rsplit_once('/').map_or(project, |(group, _)| group);
+    let mut url = Url::parse(&match prefix {
+        '#' => format!("{web_url}/{project}/-/issues/{reference}"),
+        '!' => format!("{web_url}/{project}/-/merge_requests/{reference}"),
+        '&' => format!("{web_url}/groups/{group}/-/epics/{reference}"),
+        '@' => format!("{web_url}/{reference}"),
+        '%' => format!("{web_url}/{project}/-/milestones"),
+        _ => format!("{web_url}/{project}/-/issues"),
+    })
+    .ok()?;
+    match prefix {
+        '%' => {
+            url.query_pairs_mut().append_pair("search_title", reference);
+        }
+        '~' => {
+            url.query_pairs_mut().append_pair("label_name[]", reference);
+        }
+        _ => {}
+    }
+
+    Some(url.to_string())
+}
+
+/// Builds the hover for the quick action, or the label, member or milestone
+/// reference at `position` in the document at `pathname`.
+fn hover_for_position(state: &LspState, pathname: &str, position: &Position) -> Option<Hover> {
+    let line = state
+        .sources
+        .get(pathname)?
+        .lines()
+        .nth(position.line as usize)?;
+
+    if let Some(qa) = quick_action_for_line(line) {
+        let indent = line.len() - line.trim_start().len();
+        let start = line[..indent].encode_utf16().count() as u32;
+        // the `/` plus the name
+        let end = start + 1 + qa.name.encode_utf16().count() as u32;
+        if start <= position.character && position.character <= end {
+            let reference = Reference {
+                name: qa.name.to_string(),
+                start,
+                end,
+            };
+            return Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: format!(
+                        "`{}`\n\n{}\n\n[GitLab docs](https://docs.gitlab.com/ee/user/project/quick_actions.html)",
+                        qa.usage(),
+                        qa.description,
+                    ),
+                }),
+                range: Some(reference.range(position.line)),
+            });
+        }
+    }
+
+    [
+        ('~', &state.labels),
+        ('@', &state.members),
+        ('@', &state.groups),
+        ('%', &state.milestones),
+    ]
+    .into_iter()
+    .find_map(|(prefix, resources)| {
+        let reference = find_references(line, prefix)
+            .into_iter()
+            .find(|r| r.start <= position.character && position.character <= r.end)?;
+        let description = resources
+            .iter()
+            .find(|resource| bare_name(&resource.completion) == reference.name)?
+            .description
+            .clone()?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: description,
+            }),
+            range: Some(reference.range(position.line)),
+        })
+    })
+}
+
+/// Titles longer than this (in chars) are truncated in inlay hints.
+const INLAY_HINT_MAX_LEN: usize = 30;
+
+/// Builds inlay hints annotating the issue references (eg `#123`) in `range` of
+/// the document at `pathname` with their titles. References to issues which
+/// haven't been fetched (eg closed ones) aren't annotated.
+fn inlay_hints(state: &LspState, pathname: &str, range: &Range) -> Vec<InlayHint> {
+    let Some(source) = state.sources.get(pathname) else {
+        return vec![];
+    };
+
+    let mut hints = vec![];
+    for (line_number, line) in source
+        .lines()
+        .enumerate()
+        .skip(range.start.line as usize)
+        .take((range.end.line.saturating_sub(range.start.line) + 1) as usize)
+    {
+        for reference in find_references(line, '#') {
+            let Some(title) = state
+                .issues
+                .iter()
+                .find(|issue| bare_name(&issue.completion) == reference.name)
+                .and_then(|issue| issue.description.as_ref())
+            else {
+                continue;
+            };
+
+            let truncated = title.chars().count() > INLAY_HINT_MAX_LEN;
+            let label = if truncated {
+                let title: String = title.chars().take(INLAY_HINT_MAX_LEN - 1).collect();
+                format!("{}…", title.trim_end())
+            } else {
+                title.clone()
+            };
+            hints.push(InlayHint {
+                position: Position {
+                    line: line_number as u32,
+                    character: reference.end,
+                },
+                label: InlayHintLabel::String(label),
+                kind: None,
+                text_edits: None,
+                tooltip: truncated.then(|| InlayHintTooltip::String(title.clone())),
+                padding_left: Some(true),
+                padding_right: None,
+                data: None,
+            });
+        }
+    }
+
+    hints
+}
+
+/// Returns the key under which the document at `uri` is stored in
+/// `LspState::sources`: the path for `file:` URIs, or the whole URI for any
+/// other scheme (eg `untitled:Untitled-1` for an unsaved buffer), which would
+/// otherwise be reduced to an ambiguous path like `Untitled-1`.
+fn document_key(uri: &Url) -> String {
+    match uri.scheme() {
+        "file" => uri.path().to_owned(),
+        _ => uri.to_string(),
+    }
+}
+
+/// The inverse of `document_key`.
+fn document_uri(key: &str) -> Option<Url> {
+    if key.starts_with('/') {
+        Url::from_file_path(key).ok()
+    } else {
+        Url::parse(key).ok()
+    }
+}
+
+/// Finds the label reference at `position` in the document at `pathname`.
+fn label_at_position(state: &LspState, pathname: &str, position: &Position) -> Option<Reference> {
+    let line = state
+        .sources
+        .get(pathname)?
+        .lines()
+        .nth(position.line as usize)?;
+    find_references(line, '~')
+        .into_iter()
+        .find(|r| r.start <= position.character && position.character <= r.end)
+}
+
+/// Finds all references to the label `name` across all open documents.
+fn label_locations(state: &LspState, name: &str) -> Vec<(Url, Range)> {
+    let mut pathnames: Vec<&String> = state.sources.keys().collect();
+    pathnames.sort();
+
+    let mut locations = vec![];
+    for pathname in pathnames {
+        let (Some(uri), Some(contents)) = (document_uri(pathname), state.sources.get(pathname))
+        else {
+            continue;
+        };
+        for (line_number, line) in contents.lines().enumerate() {
+            locations.extend(
+                find_references(line, '~')
+                    .into_iter()
+                    .filter(|r| r.name == name)
+                    .map(|r| (uri.clone(), r.range(line_number as u32))),
+            );
+        }
+    }
+
+    locations
+}
+
+/// A reference to a GitLab resource found in a document, eg `~bug` or
+/// `~"needs review"`.
+#[derive(Debug, PartialEq)]
+struct Reference {
+    /// Name of the referenced resource, without prefix or quotes.
+    name: String,
+    /// Offset (in UTF-16 code units, as used by LSP) of the prefix character.
+    start: u32,
+    /// Offset (in UTF-16 code units) just past the end of the reference.
+    end: u32,
+}
+
+impl Reference {
+    fn range(&self, line: u32) -> Range {
+        Range {
+            start: Position {
+                line,
+                character: self.start,
+            },
+            end: Position {
+                line,
+                character: self.end,
+            },
+        }
+    }
+}
+
+/// Characters which may appear in an unquoted reference.
+///
+/// See: https://docs.gitlab.com/ee/user/markdown.html#gitlab-specific-references
+fn is_reference_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':' | '?' | '&')
+}
+
+/// Finds all references in `line` which start with `prefix`, in both their
+/// plain (`~bug`) and quoted (`~"needs review"`) forms.
+fn find_references(line: &str, prefix: char) -> Vec<Reference> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut references = vec![];
+
+    let mut i = 0;
+    while i < chars.len() {
+        let at_boundary = i == 0 || !(is_reference_char(chars[i - 1]) || chars[i - 1] == prefix);
+        if chars[i] != prefix || !at_boundary {
+            i += 1;
+            continue;
+        }
+
+        let (name, end) = if chars.get(i + 1) == Some(&'"') {
+            match chars[i + 2..].iter().position(|c| *c == '"') {
+                Some(len) => (
+                    chars[i + 2..i + 2 + len].iter().collect::<String>(),
+                    i + 2 + len + 1,
+                ),
+                None => {
+                    i += 1;
+                    continue;
+                }
+            }
+        } else {
+            let len = chars[i + 1..]
+                .iter()
+                .take_while(|c| is_reference_char(**c))
+                .count();
+            (
+                chars[i + 1..i + 1 + len].iter().collect::<String>(),
+                i + 1 + len,
+            )
+        };
+
+        if !name.is_empty() {
+            references.push(Reference {
+                name,
+                start: utf16_len(&chars[..i]),
+                end: utf16_len(&chars[..end]),
+            });
+        }
+        i = end.max(i + 1);
+    }
+
+    references
+}
+
+/// The length of `chars` in UTF-16 code units, as LSP positions are counted.
+fn utf16_len(chars: &[char]) -> u32 {
+    chars.iter().map(|c| c.len_utf16() as u32).sum()
+}
+
+/// Replaces the text in `range` of `source` with `new_text`, as sent by the
+/// client in an incremental `textDocument/didChange`.
+fn apply_change(source: &mut String, range: Range, new_text: &str) {
+    let start = byte_offset(source, range.start);
+    let end = byte_offset(source, range.end).max(start);
+    source.replace_range(start..end, new_text);
+}
+
+/// Converts `position`, in UTF-16 code units as used by LSP, to a byte offset
+/// into `source`. Positions past the end of a line or of the document are
+/// clamped to the end of it.
+fn byte_offset(source: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match source[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return source.len(),
+        }
+    }
+
+    let line = &source[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+
+    line_start + line.len()
+}
+
+/// The word (ie reference or quick action) being typed at a position, which is
+/// completed in its entirety.
+struct CurrentWord {
+    /// The line being typed on, which may have been joined to the previous one
+    /// if a quoted reference was wrapped onto it.
+    line: String,
+    chars: Vec<char>,
+    /// The index (in chars) of the cursor within `line`.
+    cursor: usize,
+    /// The index (in chars) of the start of the word within `line`.
+    start: usize,
+    /// The index (in chars) of the end of the word within `line`.
+    end: usize,
+    /// The number of chars of the previous line (and the space) which were
+    /// joined before this one, if any.
+    offset: usize,
+    /// Whether the document is CI config, see `is_ci_config`.
+    ci_config: bool,
+    /// The first char of the word, eg `~` for a label.
+    prefix: char,
+}
+
+/// Returns the word being typed at `position` in `source`, the document at
+/// `pathname`, if it may be completed.
+fn current_word(source: &str, pathname: &str, position: &Position) -> Option<CurrentWord> {
+    let current_line = source.lines().nth(position.line as usize)?;
+    let current_chars: Vec<char> = current_line.chars().collect();
+    // the index (in chars) of the cursor, which is given in UTF-16 code units;
+    // some clients may request completions past the end of the line
+    let cursor = {
+        let mut units = 0;
+        current_chars
+            .iter()
+            .position(|c| {
+                units += c.len_utf16();
+                units > position.character as usize
+            })
+            .unwrap_or(current_chars.len())
+    };
+    let is_boundary = |c: &char| *c == ' ' || *c == '\t';
+
+    // a quoted reference may have been wrapped onto this line, eg `~"needs`
+    // and then `review`, in which case the cursor is mid-token, so the previous
+    // line is joined to this one (by a space), and the reference is completed
+    // from where it starts
+    let mid_token = !current_chars[..cursor].iter().any(is_boundary)
+        && !current_chars
+            .first()
+            .is_some_and(|c| matches!(c, '/' | '#' | '&' | '@' | '%' | '~' | '[' | '"' | '\\'));
+    let continued = position
+        .line
+        .checked_sub(1)
+        .filter(|_| mid_token)
+        .and_then(|previous| source.lines().nth(previous as usize))
+        .and_then(|previous| Some((previous, open_quoted_reference(previous)?)));
+    // the number of chars of the previous line (and the space) which were
+    // joined before this one, if any
+    let (line, offset) = match continued {
+        Some((previous, _)) => (
+            format!("{previous} {current_line}"),
+            previous.chars().count() + 1,
+        ),
+        None => (current_line.to_string(), 0),
+    };
+    let chars: Vec<char> = line.chars().collect();
+    let cursor = cursor + offset;
+
+    // the word being typed, which the completion will replace in its entirety
+    // (including the prefix), so that the prefix is never duplicated
+    let mut current_word_start = match continued {
+        Some((_, start)) => start,
+        None => chars[..cursor]
+            .iter()
+            .rposition(is_boundary)
+            .map_or(0, |i| i + 1),
+    };
+    let mut current_word_end = chars[cursor..]
+        .iter()
+        .position(is_boundary)
+        .map_or(chars.len(), |i| cursor + i);
+    // in CI config, references are written in quoted YAML strings, eg
+    // `"~bug"`, so only what's within the quotes is replaced
+    let ci_config = is_ci_config(pathname);
+    if let Some(quote @ ('"' | '\'')) = chars
+        .get(current_word_start)
+        .copied()
+        .filter(|_| ci_config && current_word_start < cursor)
+    {
+        current_word_start += 1;
+        if current_word_end > cursor && chars[current_word_end - 1] == quote {
+            current_word_end -= 1;
+        }
+    }
+    let ch = *chars.get(current_word_start)?;
+    // quick actions, and most references, don't apply to CI config, and `#`
+    // starts a YAML comment, but labels and milestones may be matched by rules,
+    // alongside variables
+    if ci_config && !matches!(ch, '~' | '%' | '$') {
+        return None;
+    }
+    // an escaped prefix (eg `\~`) is literal, and a doubled one (eg
+    // `~~strikethrough~~`) is Markdown, rather than a reference
+    if ch == '\\'
+        || (matches!(ch, '#' | '&' | '@' | '%' | '~')
+            && chars.get(current_word_start + 1) == Some(&ch))
+    {
+        return None;
+    }
+
+    Some(CurrentWord {
+        line,
+        chars,
+        cursor,
+        start: current_word_start,
+        end: current_word_end,
+        offset,
+        ci_config,
+        prefix: ch,
+    })
+}
+
+/// Builds the completions for the reference or quick action being typed at
+/// `position` in the document at `pathname`.
+fn completions_for_position(
+    state: &LspState,
+    pathname: &str,
+    position: &Position,
+) -> Option<CompletionResponse> {
+    let source = state.sources.get(pathname)?;
+    let CurrentWord {
+        line,
+        chars,
+        cursor,
+        start: current_word_start,
+        end: current_word_end,
+        offset,
+        ci_config,
+        prefix: ch,
+    } = current_word(source, pathname, position)?;
+    let line = line.as_str();
+
+    let (completions, completion_kind) = match ch {
+        '/' => (
+            QUICK_ACTIONS
+                .iter()
+                // hide quick actions which only take arguments from resources
+                // which aren't enabled
+                .filter(|qa| {
+                    qa.arguments.is_empty()
+                        || qa
+                            .arguments
+                            .iter()
+                            .any(|resource_kind| state.config.is_enabled(*resource_kind))
+                })
+                .filter(|qa| {
+                    let document_kind = match state.config.document_context {
+                        Some(document_context) => document_context.document_kind(),
+                        None => DocumentKind::for_pathname(pathname),
+                    };
+                    match (qa.context, document_kind) {
+                        (Some(context), Some(document_kind)) => context == document_kind,
+                        _ => true,
+                    }
+                })
+                .filter(|qa| {
+                    !qa.existing_only
+                        || state
+                            .config
+                            .document_context
+                            .is_some_and(DocumentContext::is_comment)
+                })
+                // hide quick actions which aren't available on the instance
+                .filter(|qa| state.tier.is_none_or(|tier| qa.tier <= tier))
+                .filter(|qa| match (qa.min_version(), state.version) {
+                    (Some(min_version), Some(version)) => min_version <= version,
+                    _ => true,
+                })
+                .filter(|qa| !qa.is_service_desk() || state.service_desk)
+                .map(|qa| CompletionItemData {
+                    completion: format!("/{}", qa.name),
+                    description: Some(qa.description.to_string()),
+                    snippet: match (qa.name, suggested_branch_name(source)) {
+                        ("create_merge_request", Some(branch)) => {
+                            Some(format!("/{} ${{1:{branch}}}", qa.name))
+                        }
+                        _ => qa.snippet.map(|snippet| format!("/{} {snippet}", qa.name)),
+                    },
+                    ..Default::default()
+                })
+                .collect::<Vec<CompletionItemData>>(),
+            Resource::QuickActions,
+        ),
+        // `#` also starts a Markdown heading, so only offer issues as the
+        // argument to a quick action which takes them
+        '#' => match quick_action_for_line(line) {
+            Some(qa) if qa.arguments.contains(&Resource::Issues) => {
+                (state.issues.iter().cloned().collect(), Resource::Issues)
+            }
+            _ => return None,
+        },
+        // `&` is common in prose, so only offer epics as the argument to a
+        // quick action which takes them
+        '&' => match quick_action_for_line(line) {
+            Some(qa) if qa.arguments.contains(&Resource::Epics) => {
+                (state.epics.iter().cloned().collect(), Resource::Epics)
+            }
+            _ => return None,
+        },
+        // contacts are referenced like `[contact:alex@example.com]`, so only
+        // offer them as the argument to a quick action which takes them
+        '[' => match quick_action_for_line(line) {
+            Some(qa) if qa.arguments.contains(&Resource::Contacts) && !qa.is_service_desk() => {
+                (state.contacts.iter().cloned().collect(), Resource::Contacts)
+            }
+            _ => return None,
+        },
+        '@' => {
+            let mut members: Vec<CompletionItemData> = state.members.iter().cloned().collect();
+            if quick_action_for_line(line).is_some_and(QuickAction::accepts_me) {
+                members.extend(state.current_user.clone());
+            }
+            // don't offer members who are already mentioned by the quick
+            // action, eg `/assign @alex @` shouldn't offer `@alex` again; unlike
+            // labels, usernames are case-insensitive
+            let mentioned: HashSet<String> = match quick_action_for_line(line) {
+                Some(qa) if qa.arguments.contains(&Resource::Members) => find_references(line, '@')
+                    .into_iter()
+                    .filter(|r| r.start != utf16_len(&chars[..current_word_start]))
+                    .map(|r| r.name.to_lowercase())
+                    .collect(),
+                _ => HashSet::new(),
+            };
+            members.retain(|member| {
+                !mentioned.contains(&bare_name(&member.completion).to_lowercase())
+            });
+            (members, Resource::Members)
+        }
+        '%' => (
+            state.milestones.iter().cloned().collect(),
+            Resource::Milestones,
+        ),
+        // the predefined variables, and those of the project, if enabled
+        '$' if ci_config => (
+            PREDEFINED_CI_VARIABLES
+                .iter()
+                .map(|(key, description)| CompletionItemData {
+                    completion: format!("${key}"),
+                    description: Some(description.to_string()),
+                    snippet: None,
+                    ..Default::default()
+                })
+                .chain(state.ci_variables.iter().cloned())
+                .collect(),
+            Resource::CiVariables,
+        ),
+        // boards' lists are referenced by their labels
+        '~' if quick_action_for_line(line)
+            .is_some_and(|qa| qa.arguments.contains(&Resource::Boards)) =>
+        {
+            (state.boards.iter().cloned().collect(), Resource::Boards)
+        }
+        '~' => {
+            // don't offer labels which are already applied by the quick action,
+            // eg `/label ~bug ~` shouldn't offer `~bug` again; like GitLab, this
+            // is case-sensitive, so `~Bug` is still offered
+            let applied: HashSet<String> = match quick_action_for_line(line) {
+                Some(qa) if qa.arguments.contains(&Resource::Labels) => find_references(line, '~')
+                    .into_iter()
+                    .filter(|r| r.start != utf16_len(&chars[..current_word_start]))
+                    .map(|r| r.name)
+                    .collect(),
+                _ => HashSet::new(),
+            };
+            (
+                state
+                    .labels
+                    .iter()
+                    .filter(|label| {
+                        let name = bare_name(&label.completion);
+                        state.config.offers_label(&name) && !applied.contains(&name)
+                    })
+                    .cloned()
+                    .collect(),
+                Resource::Labels,
+            )
+        }
+        // branches and projects are referenced by their name or path alone, so
+        // only offer them as the argument to a quick action which takes them
+        _ => match quick_action_for_line(line) {
+            Some(qa) if qa.arguments.contains(&Resource::Branches) => {
+                (state.branches.iter().cloned().collect(), Resource::Branches)
+            }
+            Some(qa) if qa.arguments.contains(&Resource::Projects) => {
+                (state.projects.iter().cloned().collect(), Resource::Projects)
+            }
+            // emails are free-form, but contacts' are suggested
+            Some(qa) if qa.is_service_desk() => (
+                state
+                    .contacts
+                    .iter()
+                    .map(|contact| CompletionItemData {
+                        completion: bare_name(&contact.completion),
+                        ..contact.clone()
+                    })
+                    .collect(),
+                Resource::Contacts,
+            ),
+            Some(qa) if qa.arguments.contains(&Resource::Severities) => (
+                SEVERITIES
+                    .iter()
+                    .map(|(severity, alias)| CompletionItemData {
+                        completion: severity.to_string(),
+                        description: Some(format!("Also `{alias}`")),
+                        snippet: None,
+                        ..Default::default()
+                    })
+                    .collect(),
+                Resource::Severities,
+            ),
+            Some(qa) if qa.arguments.contains(&Resource::DueDates) => (
+                due_date_completions(today(state.config.utc_offset)),
+                Resource::DueDates,
+            ),
+            Some(qa) if qa.arguments.contains(&Resource::Emoji) => (
+                EMOJI
+                    .iter()
+                    .map(|(name, emoji)| CompletionItemData {
+                        completion: format!(":{name}:"),
+                        description: Some(emoji.to_string()),
+                        snippet: None,
+                        ..Default::default()
+                    })
+                    .collect(),
+                Resource::Emoji,
+            ),
+            Some(qa) if qa.arguments.contains(&Resource::HealthStatuses) => (
+                HEALTH_STATUSES
+                    .iter()
+                    .map(|(status, name)| CompletionItemData {
+                        completion: status.to_string(),
+                        description: Some(name.to_string()),
+                        snippet: None,
+                        ..Default::default()
+                    })
+                    .collect(),
+                Resource::HealthStatuses,
+            ),
+            // `me` is the only argument which isn't a reference
+            Some(qa) if qa.accepts_me() => (
+                state.current_user.iter().cloned().collect(),
+                Resource::Members,
+            ),
+            _ => return None,
+        },
+    };
+
+    let mut completions: Vec<(Resource, CompletionItemData)> = completions
+        .into_iter()
+        .map(|comp| (completion_kind, comp))
+        .collect();
+    // whole groups can be mentioned, alongside individual members
+    if let (Resource::Members, '@') = (completion_kind, ch) {
+        completions.extend(
+            state
+                .groups
+                .iter()
+                .map(|group| (Resource::Groups, group.clone())),
+        );
+    }
+
+    // let editors fall back to their own completion, rather than showing an
+    // empty popup
+    if completions.is_empty() {
+        return None;
+    }
+
+    let typed: String = chars[current_word_start..cursor].iter().collect();
+    let typed = bare_name(&typed).trim_start_matches('"').to_lowercase();
+
+    // wait until enough has been typed to narrow down fetched resources (eg
+    // `@ab` rather than `@`), asking the client to request completions again as
+    // the user keeps typing
+    if FETCHED_RESOURCES.contains(&completion_kind)
+        && typed.chars().count() < state.config.min_chars_before_complete
+    {
+        return Some(CompletionResponse::List(CompletionList {
+            is_incomplete: true,
+            items: vec![],
+        }));
+    }
+
+    // bound the size of the response by only offering those which match what's
+    // been typed so far, and asking the client to request completions again as
+    // the user keeps typing; this matches case-insensitively, like editors' own
+    // filtering, unlike matching of label names
+    let max_items = state.config.max_completion_items();
+    let is_incomplete = completions.len() > max_items;
+    if is_incomplete {
+        let key = (completion_kind, line.to_string(), cursor);
+        let cached = state
+            .completion_cache
+            .lock()
+            .ok()
+            .and_then(|mut cache| cache.get(&key));
+        match cached {
+            Some(cached) => completions = cached,
+            None => {
+                completions.retain(|(_, comp)| {
+                    bare_name(&comp.completion).to_lowercase().contains(&typed)
+                });
+                // prefer those which start with what's been typed
+                completions.sort_by_cached_key(|(_, comp)| {
+                    let name = bare_name(&comp.completion);
+                    (!name.to_lowercase().starts_with(&typed), name)
+                });
+                if let Ok(mut cache) = state.completion_cache.lock() {
+                    cache.insert(key, completions.clone());
+                }
+            }
+        }
+        completions.truncate(max_items);
+    }
+
+    // the position of a char of the (possibly joined) line, which is on the
+    // previous line if it's before the joined ones
+    let position_of = |index: usize| -> Position {
+        let (line, start) = if index < offset {
+            (position.line - 1, 0)
+        } else {
+            (position.line, offset)
+        };
+        Position {
+            line,
+            character: utf16_len(&chars[start..index]),
+        }
+    };
+    let range = Range {
+        start: position_of(current_word_start),
+        end: position_of(current_word_end),
+    };
+
+    let completions: Vec<CompletionItem> = completions
+        .iter()
+        .map(|(completion_kind, comp)| {
+            // offer `me` and the project's default branch before the others
+            let preferred = match completion_kind {
+                Resource::Branches => state.default_branch.as_ref() == Some(&comp.completion),
+                Resource::Members => state.current_user.as_ref() == Some(comp),
+                _ => false,
+            };
+            let detail = match completion_kind {
+                Resource::Boards => "board list",
+                Resource::Branches if preferred => "default branch",
+                Resource::Branches => "branch",
+                Resource::CiVariables => "CI/CD variable",
+                Resource::Contacts => "contact",
+                Resource::DueDates => "date",
+                Resource::Emoji => "emoji",
+                Resource::Epics => "epic",
+                Resource::Groups => "group",
+                Resource::HealthStatuses => "health status",
+                Resource::Issues => "issue",
+                Resource::Labels => "label",
+                Resource::Members => "username",
+                Resource::Milestones => "milestone",
+                Resource::Projects => "project",
+                Resource::QuickActions => "quick action",
+                Resource::Severities => "severity",
+            };
+            let text = if state.config.trailing_space(*completion_kind) && !ci_config {
+                format!("{} ", comp.completion)
+            } else {
+                comp.completion.clone()
+            };
+            let mut completion = CompletionItem::new_simple(text.clone(), detail.to_string());
+
+            completion.kind = Some(state.config.completion_kind(*completion_kind));
+            completion.documentation = if state.client_support.markdown_documentation {
+                // link to the item's page, eg a label's issues, for editors
+                // which render Markdown
+                let url = comp.url.clone().or_else(|| {
+                    let prefix = match completion_kind {
+                        Resource::Labels => '~',
+                        Resource::Members => '@',
+                        Resource::Milestones => '%',
+                        _ => return None,
+                    };
+                    reference_url(&state.config, prefix, &bare_name(&comp.completion))
+                });
+                let link = url.map(|url| format!("[Open in GitLab]({url})"));
+                match (comp.description.clone(), link) {
+                    (Some(description), Some(link)) => Some(format!("{description}\n\n{link}")),
+                    (description, link) => description.or(link),
+                }
+                .map(|value| {
+                    Documentation::MarkupContent(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value,
+                    })
+                })
+            } else {
+                comp.description.clone().map(Documentation::String)
+            };
+            // fall back to plain text for clients which don't support snippets
+            let snippet = comp
+                .snippet
+                .as_ref()
+                .filter(|_| state.client_support.snippets);
+            completion.text_edit = Some(CompletionTextEdit::Edit(TextEdit {
+                range,
+                new_text: snippet.cloned().unwrap_or(text),
+            }));
+            if snippet.is_some() {
+                completion.insert_text_format = Some(InsertTextFormat::SNIPPET);
+            }
+            // don't let editors reindent, eg, a quick action at the start of
+            // a line
+            completion.insert_text_mode = Some(InsertTextMode::AS_IS);
+            // let editors fuzzy match on the name alone, eg `prio` should
+            // match `~priority::high`
+            completion.filter_text = Some(bare_name(&comp.completion));
+            // group scoped labels by their scope, eg `priority::high` sorts
+            // as `priority/high`, alongside `priority/low`
+            if let Resource::Labels = completion_kind {
+                completion.sort_text = Some(bare_name(&comp.completion).replace("::", "/"));
+            }
+            // put those with the most access first (eg owners and maintainers),
+            // who are most likely to be assigned or reviewing, then any groups
+            if let Resource::Members | Resource::Groups = completion_kind {
+                if !state.config.members_sort_alphabetical {
+                    let access_level = state
+                        .member_data
+                        .get(&bare_name(&comp.completion))
+                        .map_or(0, |member| member.access_level);
+                    let rank = 99 - access_level.min(99);
+                    completion.sort_text =
+                        Some(format!("{rank:02}_{}", bare_name(&comp.completion)));
+                }
+            }
+            // put the labels and milestones used most by recently updated
+            // issues and merge requests first
+            if let (Resource::Labels | Resource::Milestones, true) =
+                (completion_kind, state.config.rank_by_recency)
+            {
+                let key = (*completion_kind, bare_name(&comp.completion));
+                let rank = 999 - state.recent_uses.get(&key).copied().unwrap_or(0).min(999);
+                let name = completion
+                    .sort_text
+                    .take()
+                    .unwrap_or_else(|| bare_name(&comp.completion));
+                completion.sort_text = Some(format!("{rank:03}_{name}"));
+            }
+            if preferred {
+                completion.sort_text = Some("!".to_string());
+            }
+
+            completion
+        })
+        .collect();
+
+    if is_incomplete {
+        Some(CompletionResponse::List(CompletionList {
+            is_incomplete,
+            items: completions,
+        }))
+    } else {
+        Some(CompletionResponse::Array(completions))
+    }
+}
+
+/// Returns the root of the workspace, in which to look for the config file and
+/// git remote: the first of the workspace folders, or otherwise the root URI
+/// sent by older clients, or otherwise `cwd` (ie the server's working
+/// directory).
+fn workspace_root(params: &InitializeParams, cwd: Option<PathBuf>) -> Option<PathBuf> {
+    #[allow(deprecated)]
+    let root_uri = params.root_uri.as_ref();
+    params
+        .workspace_folders
+        .as_ref()
+        .and_then(|folders| folders.first())
+        .map(|folder| &folder.uri)
+        .or(root_uri)
+        .and_then(|uri| uri.to_file_path().ok())
+        .or(cwd)
+}
+
+/// Returns the kinds of resources which may be completed at `position`, from
+/// the prefix of the word there, eg members and groups after `@`.
+fn resources_for_position(state: &LspState, pathname: &str, position: &Position) -> Vec<Resource> {
+    let Some(word) = state
+        .sources
+        .get(pathname)
+        .and_then(|source| current_word(source, pathname, position))
+    else {
+        return vec![];
+    };
+    // as in `completions_for_position`, some resources are only completed as
+    // the argument to a quick action which takes them
+    let quick_action = quick_action_for_line(&word.line);
+    let takes =
+        |resource_kind| quick_action.is_some_and(|qa| qa.arguments.contains(&resource_kind));
+
+    match word.prefix {
+        '@' => vec![Resource::Members, Resource::Groups],
+        '~' if takes(Resource::Boards) => vec![Resource::Boards],
+        '~' => vec![Resource::Labels],
+        '%' => vec![Resource::Milestones],
+        '$' if word.ci_config => vec![Resource::CiVariables],
+        '#' if takes(Resource::Issues) => vec![Resource::Issues],
+        '&' if takes(Resource::Epics) => vec![Resource::Epics],
+        '[' if takes(Resource::Contacts) => vec![Resource::Contacts],
+        '/' | '#' | '&' | '[' => vec![],
+        _ if takes(Resource::Branches) => vec![Resource::Branches],
+        _ if takes(Resource::Projects) => vec![Resource::Projects],
+        _ if quick_action.is_some_and(QuickAction::is_service_desk) => vec![Resource::Contacts],
+        _ => vec![],
+    }
+}
+
+/// Returns the name of a completion without its prefix, quotes or trailing
+/// space, eg `~"needs review" ` becomes `needs review`.
+fn bare_name(completion: &str) -> String {
+    let name = completion.trim_end();
+    let name = match name.strip_prefix("[contact:") {
+        Some(contact) => contact.strip_suffix(']').unwrap_or(contact),
+        None => name
+            .strip_prefix(['/', '#', '&', '@', '%', '~'])
+            .unwrap_or(name),
+    };
+    name.strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+        .unwrap_or(name)
+        .to_string()
+}
+
+fn gitlab_resource_url(
+    api_base: &str,
+    project: &str,
+    members_scope: MembersScope,
+    resource_kind: &Resource,
+) -> String {
+    let api_base = api_base.strip_suffix("/").unwrap_or(api_base);
+    if let Resource::Projects = resource_kind {
+        // projects which the user is a member of, rather than those of the
+        // current project
+        return format!("{api_base}/projects?membership=true&simple=true&per_page=100");
+    }
+    if let Resource::Epics = resource_kind {
+        // epics belong to the project's group, rather than the project itself
+        let group = project.rsplit_once('/').map_or(project, |(group, _)| group);
+        let group = group.replace('/', "%2F");
+        return format!("{api_base}/groups/{group}/epics?state=opened&per_page=100");
+    }
+
+    let project = project.replace('/', "%2F");
+    let resource = match resource_kind {
+        Resource::Boards => "boards",
+        Resource::Branches => "repository/branches",
+        Resource::CiVariables => "variables",
+        // the project's ancestor groups, and those it's shared with
+        Resource::Groups => "groups?with_shared=true",
+        Resource::Issues => "issues?state=opened",
+        Resource::Labels => "labels",
+        Resource::Members => match members_scope {
+            MembersScope::All => "members/all",
+            MembersScope::Direct => "members",
+        },
+        Resource::Milestones => "milestones",
+        Resource::Contacts
+        | Resource::DueDates
+        | Resource::Emoji
+        | Resource::Epics
+        | Resource::HealthStatuses
+        | Resource::Projects
+        | Resource::QuickActions
+        | Resource::Severities => unreachable!(),
+    };
+    let separator = if resource.contains('?') { '&' } else { '?' };
+    // See: https://docs.gitlab.com/ee/api/rest/index.html#offset-based-pagination
+    format!("{api_base}/projects/{project}/{resource}{separator}per_page=100")
+}
+
+/// Whether the label `name` matches `pattern`, which is either a glob where `*`
+/// matches any characters (eg `bot::*`), or otherwise a prefix (eg `bot::`).
+/// Label names are case-sensitive in GitLab (eg `~Bug` and `~bug` may both
+/// exist), so matching is too.
+fn label_matches(pattern: &str, name: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return name.starts_with(pattern);
+    };
+    let Some(mut remaining) = name.strip_prefix(prefix) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let suffix = parts.pop().unwrap_or_default();
+    for part in parts {
+        match remaining.find(part) {
+            Some(i) => remaining = &remaining[i + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.ends_with(suffix)
+}
+
+/// Normalizes a project path which may have been pasted from elsewhere, eg
+/// `https://gitlab.com/group/project.git` or `/group/project/` both become
+/// `group/project`.
+fn normalize_project(project: &str) -> String {
+    let project = project.trim();
+    let project = match project.split_once("://") {
+        Some((_scheme, rest)) => rest.split_once('/').map_or("", |(_host, path)| path),
+        None => project,
+    };
+    // eg https://gitlab.com/group/project/-/issues
+    let project = project.split_once("/-/").map_or(project, |(path, _)| path);
+    let project = project.trim_matches('/');
+    project.strip_suffix(".git").unwrap_or(project).to_string()
+}
+
+/// Returns `value` as a list of strings, if it is one.
+fn string_array(value: &Value) -> Option<Vec<String>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|value| value.as_str().map(str::to_string))
+        .collect()
+}
+
+/// The error for a config file which can't be parsed.
+fn invalid_config_file_error(err: &str) -> Error {
+    Error {
+        code: ErrorCode::ServerError(1),
+        message: format!("Error: invalid config file {err}").into(),
+        data: Some(json!({
+            "kind": "invalid_config_file",
+            "file": config_file::FILENAME,
+        })),
+    }
+}
+
+/// The error for a missing (or empty) `project`, which is required.
+fn missing_project_error() -> Error {
+    Error {
+        code: ErrorCode::ServerError(1),
+        message: "Error: required configuration param 'project' not supplied".into(),
+        data: Some(json!({
+            "kind": "missing_param",
+            "param": "project",
+        })),
+    }
+}
+
+/// The error for an API token which GitLab rejects (ie a 401 response), as
+/// it's invalid or has expired, rather than lacking a scope (ie a 403).
+fn token_invalid_error() -> Error {
+    Error {
+        code: ErrorCode::ServerError(1),
+        message: "Error: GitLab rejected the API token, as it's invalid or has expired; \
+            please refresh it"
+            .into(),
+        data: Some(json!({ "kind": "token_invalid" })),
+    }
+}
+
+/// Parses the name of a kind of completion, eg `enum_member`.
+fn parse_completion_item_kind(name: &str) -> Option<CompletionItemKind> {
+    let completion_kind = match name {
+        "text" => CompletionItemKind::TEXT,
+        "method" => CompletionItemKind::METHOD,
+        "function" => CompletionItemKind::FUNCTION,
+        "constructor" => CompletionItemKind::CONSTRUCTOR,
+        "field" => CompletionItemKind::FIELD,
+        "variable" => CompletionItemKind::VARIABLE,
+        "class" => CompletionItemKind::CLASS,
+        "interface" => CompletionItemKind::INTERFACE,
+        "module" => CompletionItemKind::MODULE,
+        "property" => CompletionItemKind::PROPERTY,
+        "unit" => CompletionItemKind::UNIT,
+        "value" => CompletionItemKind::VALUE,
+        "enum" => CompletionItemKind::ENUM,
+        "keyword" => CompletionItemKind::KEYWORD,
+        "snippet" => CompletionItemKind::SNIPPET,
+        "color" => CompletionItemKind::COLOR,
+        "file" => CompletionItemKind::FILE,
+        "reference" => CompletionItemKind::REFERENCE,
+        "folder" => CompletionItemKind::FOLDER,
+        "enum_member" => CompletionItemKind::ENUM_MEMBER,
+        "constant" => CompletionItemKind::CONSTANT,
+        "struct" => CompletionItemKind::STRUCT,
+        "event" => CompletionItemKind::EVENT,
+        "operator" => CompletionItemKind::OPERATOR,
+        "type_parameter" => CompletionItemKind::TYPE_PARAMETER,
+        _ => return None,
+    };
+    Some(completion_kind)
+}
+
+/// An error for a configuration param which was supplied with an invalid value.
+fn invalid_param_error(name: &str, expected: &str) -> Error {
+    Error {
+        code: ErrorCode::ServerError(1),
+        message: format!(
+            "Error: invalid configuration param '{name}' supplied, expected {expected}"
+        )
+        .into(),
+        data: Some(json!({
+            "kind": "invalid_param",
+            "param": name,
+        })),
+    }
+}
+
+/// Loads the PEM encoded certificate at `path`, eg for `ca_cert_path`.
+fn load_certificate(path: &str) -> Result<reqwest::Certificate> {
+    let error = |message: String| Error {
+        code: ErrorCode::ServerError(1),
+        message: message.into(),
+        data: Some(json!({
+            "kind": "invalid_ca_cert",
+            "path": path,
+        })),
+    };
+    let pem = std::fs::read(path).map_err(|err| {
+        error(format!(
+            "Error: unable to read ca_cert_path `{path}`: {err}"
+        ))
+    })?;
+    reqwest::Certificate::from_pem(&pem).map_err(|err| {
+        error(format!(
+            "Error: ca_cert_path `{path}` is not a valid PEM certificate: {err}"
+        ))
+    })
+}
+
+/// Expands references to environment variables, like `${CI_PROJECT_PATH}`, in
+/// the value of the configuration param `name`.
+fn expand_env_param(name: &str, value: &str) -> Result<String> {
+    expand_env(value, |var| std::env::var(var).ok()).map_err(|var| Error {
+        code: ErrorCode::ServerError(1),
+        message: format!(
+            "Error: configuration param '{name}' refers to unset environment variable '{var}'"
+        )
+        .into(),
+        data: Some(json!({
+            "kind": "unset_env",
+            "param": name,
+            "env": var,
+        })),
+    })
+}
+
+/// Replaces each `${VAR}` in `value` with the value of `VAR`, according to
+/// `lookup`. Returns the name of the first variable which isn't set, if any.
+fn expand_env(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> std::result::Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let var = &rest[start + 2..start + 2 + len];
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&lookup(var).ok_or_else(|| var.to_string())?);
+        rest = &rest[start + 2 + len + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Returns a warning if the token which expires on `expires_at` (eg
+/// `2024-06-30`) will do so within `warning_days` of `today`.
+fn token_expiry_warning(
+    expires_at: &str,
+    today: chrono::NaiveDate,
+    warning_days: u32,
+) -> Option<String> {
+    let expiry = chrono::NaiveDate::parse_from_str(expires_at.get(..10)?, "%Y-%m-%d").ok()?;
+    let days_left = (expiry - today).num_days();
+    match days_left {
+        ..=-1 => Some(format!("Your GitLab API token expired on {expires_at}")),
+        0 => Some("Your GitLab API token expires today".to_string()),
+        1 => Some(format!(
+            "Your GitLab API token expires tomorrow, on {expires_at}"
+        )),
+        days if days <= i64::from(warning_days) => Some(format!(
+            "Your GitLab API token expires in {days} days, on {expires_at}"
+        )),
+        _ => None,
+    }
+}
+
+/// Returns today's date, in the time zone with `utc_offset`, or otherwise in
+/// the server's local time zone.
+fn today(utc_offset: Option<chrono::FixedOffset>) -> chrono::NaiveDate {
+    match utc_offset {
+        Some(offset) => chrono::Utc::now().with_timezone(&offset).date_naive(),
+        None => chrono::Local::now().date_naive(),
+    }
+}
+
+/// Returns suggested dates for `/due`, as ISO 8601 dates relative to `today`,
+/// eg the end of this week or month.
+fn due_date_completions(today: chrono::NaiveDate) -> Vec<CompletionItemData> {
+    use chrono::{Datelike, Days, Months, NaiveDate};
+
+    // weeks start on Monday, as in ISO 8601
+    let weekday = u64::from(today.weekday().num_days_from_monday());
+    // the last day of the month which is `months` after this one
+    let end_of_month = |months: u32| {
+        NaiveDate::from_ymd_opt(today.year(), today.month(), 1)
+            .and_then(|first| first.checked_add_months(Months::new(months + 1)))
+            .and_then(|first| first.pred_opt())
+    };
+    let quarter = today.month0() / 3 + 1;
+
+    let dates = [
+        (Some(today), "Today".to_string()),
+        (today.succ_opt(), "Tomorrow".to_string()),
+        (
+            today.checked_add_days(Days::new(6 - weekday)),
+            "End of this week".to_string(),
+        ),
+        (
+            today.checked_add_days(Days::new(7 - weekday)),
+            "Start of next week".to_string(),
+        ),
+        (end_of_month(0), "End of this month".to_string()),
+        (
+            end_of_month(quarter * 3 - today.month()),
+            format!("End of this quarter (Q{quarter})"),
+        ),
+    ];
+
+    let mut seen = HashSet::new();
+    dates
+        .into_iter()
+        .filter_map(|(date, description)| Some((date?, description)))
+        // eg the end of the month may also be the end of the week
+        .filter(|(date, _)| seen.insert(*date))
+        .map(|(date, description)| CompletionItemData {
+            completion: date.format("%Y-%m-%d").to_string(),
+            description: Some(format!("{description}, {}", date.format("%A"))),
+            snippet: None,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Runs `command` with the shell, returning its (trimmed) output as the API
+/// token. The token itself is never included in errors or logs.
+async fn token_from_command(command: &str) -> Result<String> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    // the command may take a while, eg to unlock a password manager, so it's
+    // run off of the async runtime
+    let output = tokio::task::spawn_blocking(move || shell.output())
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+
+    let error = |message: String| Error {
+        code: ErrorCode::ServerError(1),
+        message: message.into(),
+        data: Some(json!({
+            "kind": "token_command_failed",
+            "command": command,
+        })),
+    };
+    match output {
+        Ok(output) if output.status.success() => {
+            let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if token.is_empty() {
+                Err(error(format!(
+                    "Error: token_command `{command}` did not output a token"
+                )))
+            } else {
+                Ok(token)
+            }
+        }
+        Ok(output) => Err(error(format!(
+            "Error: token_command `{command}` failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+        Err(err) => Err(error(format!(
+            "Error: token_command `{command}` could not be run: {err}"
+        ))),
+    }
+}
+
+fn gitlab_graphql_url(api_base: &str) -> String {
+    let api_base = api_base.strip_suffix("/").unwrap_or(api_base);
+    let api_base = api_base.strip_suffix("/v4").unwrap_or(api_base);
+    format!("{api_base}/graphql")
+}
+
+/// Logs the progress of requests to the GitLab API from their spawned tasks,
+/// tagged with an ID (eg `labels/2` for the 2nd page of labels) so that the
+/// interleaved lines of concurrent requests can be told apart.
+#[derive(Clone)]
+struct RequestLogger {
+    client: Client,
+    level: LogLevel,
+    /// See `Lsp::deprecations`.
+    deprecations: std::sync::Arc<std::sync::Mutex<HashSet<Resource>>>,
+}
+
+impl RequestLogger {
+    async fn debug(&self, id: &str, message: String) {
+        if self.level == LogLevel::Debug {
+            self.client
+                .log_message(MessageType::LOG, format!("[{id}] {message}"))
+                .await;
+        }
+    }
+
+    /// Warns the user, once per kind of resource, if the API used to fetch it
+    /// is deprecated, as GitLab reports with the `Deprecation` and `Sunset`
+    /// headers of its responses.
+    async fn warn_if_deprecated(
+        &self,
+        resource_kind: Resource,
+        headers: &reqwest::header::HeaderMap,
+    ) {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let Some(warning) =
+            deprecation_warning(resource_kind, header("deprecation"), header("sunset"))
+        else {
+            return;
+        };
+        let first = self
+            .deprecations
+            .lock()
+            .is_ok_and(|mut deprecations| deprecations.insert(resource_kind));
+        if first {
+            self.client
+                .show_message(MessageType::WARNING, warning)
+                .await;
+        }
+    }
+}
+
+/// Returns a warning that the API used to fetch `resource_kind` is deprecated,
+/// if its response had a `Deprecation` header, along with when it will be
+/// removed, if it had a `Sunset` header.
+///
+/// See: https://datatracker.ietf.org/doc/html/rfc8594
+fn deprecation_warning(
+    resource_kind: Resource,
+    deprecation: Option<&str>,
+    sunset: Option<&str>,
+) -> Option<String> {
+    // `Deprecation: false` was allowed by earlier drafts
+    deprecation.filter(|deprecation| deprecation.trim() != "false")?;
+    let removal = match sunset {
+        Some(sunset) => format!("will be removed after {}", sunset.trim()),
+        None => "may be removed".to_string(),
+    };
+    Some(format!(
+        "The GitLab API used to fetch {} is deprecated, and {removal}, so they may \
+        no longer be completed; please check for an update of the language server",
+        resource_kind.name()
+    ))
+}
+
+/// What's needed to make requests to the GitLab API, which is shared by all of
+/// the requests made while fetching resources.
+#[derive(Clone)]
+struct RequestContext {
+    logger: RequestLogger,
+    limiter: std::sync::Arc<tokio::sync::Semaphore>,
+    client: reqwest::Client,
+    api_base: String,
+    api_key: String,
+}
+
+/// Why a request to the GitLab API failed.
+#[derive(Debug, PartialEq)]
+enum RequestError {
+    /// GitLab responded with an error, eg `401 Unauthorized` for an invalid
+    /// token.
+    Status(reqwest::StatusCode),
+    /// The response wasn't JSON, eg the login page of a proxy in front of the
+    /// instance.
+    InvalidJson,
+    /// GitLab couldn't be reached, eg while offline.
+    Unreachable(String),
+}
+
+fn make_request(
+    context: &RequestContext,
+    project: &str,
+    members_scope: MembersScope,
+    resource_kind: Resource,
+) -> tokio::task::JoinHandle<(Resource, std::result::Result<Value, RequestError>)> {
+    let RequestContext {
+        logger,
+        limiter,
+        client,
+        api_base,
+        api_key,
+    } = context.clone();
+
+    // contacts are only available via GraphQL, and belong to the root group
+    // See: https://docs.gitlab.com/ee/api/graphql/reference/#groupcontacts
+    if let Resource::Contacts = resource_kind {
+        let url = gitlab_graphql_url(&api_base);
+        let full_path = project.split('/').next().unwrap_or(project).to_string();
+        return tokio::spawn(async move {
+            // paginated by cursor, starting from the first page
+            let json = fetch_all_pages(Value::Null, |page, after| {
+                let id = format!("{}/{page}", resource_kind.name());
+                let logger = logger.clone();
+                let request = client.post(&url).bearer_auth(&api_key).json(&json!({
+                    "query": "query($fullPath: ID!, $after: String) { group(fullPath: $fullPath) { contacts(after: $after) { nodes { email firstName lastName } pageInfo { hasNextPage endCursor } } } }",
+                    "variables": {
+                        "fullPath": full_path,
+                        "after": after,
+                    },
+                }));
+                let limiter = limiter.clone();
+                async move {
+                    with_permit(&limiter, async move {
+                        logger.debug(&id, "POST graphql".to_string()).await;
+                        let res = request
+                            .send()
+                            .await
+                            .map_err(|err| RequestError::Unreachable(err.to_string()))?;
+                        let status = res.status();
+                        logger.debug(&id, format!("{status}")).await;
+                        if !status.is_success() {
+                            return Err(RequestError::Status(status));
+                        }
+                        res.json::<Value>()
+                            .await
+                            .map(|json| contacts_page(&json))
+                            .map_err(|_| RequestError::InvalidJson)
+                    })
+                    .await
+                }
+            })
+            .await;
+            (resource_kind, json)
+        });
+    }
+
+    let url = gitlab_resource_url(&api_base, project, members_scope, &resource_kind);
+    tokio::spawn(async move {
+        let json = fetch_all_pages(url, |page, url| {
+            let id = format!("{}/{page}", resource_kind.name());
+            let logger = logger.clone();
+            let message = format!("GET {url}");
+            let request = client.get(url).bearer_auth(&api_key);
+            let limiter = limiter.clone();
+            async move {
+                with_permit(&limiter, async move {
+                    logger.debug(&id, message).await;
+                    let res = request
+                        .send()
+                        .await
+                        .map_err(|err| RequestError::Unreachable(err.to_string()))?;
+                    let status = res.status();
+                    logger
+                        .warn_if_deprecated(resource_kind, res.headers())
+                        .await;
+                    if !status.is_success() {
+                        logger.debug(&id, format!("{status}")).await;
+                        return Err(RequestError::Status(status));
+                    }
+                    let headers = res.headers();
+                    let no_pages = headers
+                        .get("x-total-pages")
+                        .and_then(|pages| pages.to_str().ok())
+                        .is_some_and(|pages| pages.trim() == "0");
+                    let next_url = headers
+                        .get(reqwest::header::LINK)
+                        .and_then(|link| link.to_str().ok())
+                        .and_then(next_page_url)
+                        .filter(|_| !no_pages);
+                    let Ok(json) = res.json::<Value>().await else {
+                        logger.debug(&id, format!("{status}, not JSON")).await;
+                        return Err(RequestError::InvalidJson);
+                    };
+                    let summary = match json {
+                        Value::Array(ref items) => format!("{} items", items.len()),
+                        _ => "not a list".to_string(),
+                    };
+                    let next = if next_url.is_some() {
+                        ", more to fetch"
+                    } else {
+                        ""
+                    };
+                    logger
+                        .debug(&id, format!("{status}, {summary}{next}"))
+                        .await;
+                    Ok((next_url, json))
+                })
+                .await
+            }
+        })
+        .await;
+        (resource_kind, json)
+    })
+}
+
+/// Runs `request` once `limiter` has a permit available, so that only as many
+/// requests as it has permits are in flight at once.
+async fn with_permit<T>(
+    limiter: &tokio::sync::Semaphore,
+    request: impl std::future::Future<Output = T>,
+) -> T {
+    // the semaphore is never closed, so this always gets a permit
+    let _permit = limiter.acquire().await.ok();
+    request.await
+}
+
+/// Returns the names of the labels applied to `items`, eg issues.
+fn used_label_names<'a>(items: impl Iterator<Item = &'a Value>) -> HashSet<String> {
+    items
+        .filter_map(|item| item["labels"].as_array())
+        .flatten()
+        .filter_map(|label| label.as_str().map(str::to_string))
+        .collect()
+}
+
+/// Returns the details of `labels`, as fetched from the API, by their names.
+///
+/// See: https://docs.gitlab.com/ee/api/labels.html#list-labels
+fn process_label_data(labels: &[Value]) -> HashMap<String, LabelData> {
+    labels
+        .iter()
+        .filter_map(|label| {
+            let name = label["name"].as_str()?;
+            let data = LabelData {
+                id: label["id"].as_u64()?,
+                color: label["color"].as_str().map(str::to_string),
+            };
+            Some((name.to_string(), data))
+        })
+        .collect()
+}
+
+/// Returns the details of `members`, as fetched from the API, by their
+/// usernames.
+///
+/// See: https://docs.gitlab.com/ee/api/members.html#list-all-members-of-a-group-or-project
+fn process_member_data(members: &[Value]) -> HashMap<String, MemberData> {
+    members
+        .iter()
+        .filter_map(|member| {
+            let username = member["username"].as_str()?;
+            let data = MemberData {
+                access_level: member["access_level"].as_u64()?,
+            };
+            Some((username.to_string(), data))
+        })
+        .collect()
+}
+
+/// Returns the name of the project's default branch among `branches`, as
+/// fetched from the API.
+///
+/// See: https://docs.gitlab.com/ee/api/branches.html#list-repository-branches
+fn process_default_branch(branches: &[Value]) -> Option<String> {
+    branches
+        .iter()
+        .find(|branch| branch["default"] == Value::Bool(true))
+        .and_then(|branch| branch["name"].as_str())
+        .map(str::to_string)
+}
+
+/// Counts how many of `items` (eg issues) use each label and milestone, by
+/// their kind and name.
+fn recent_uses<'a>(items: impl Iterator<Item = &'a Value>) -> HashMap<(Resource, String), usize> {
+    let mut uses = HashMap::new();
+    for item in items {
+        let labels = item["labels"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|label| label.as_str())
+            .map(|label| (Resource::Labels, label.to_string()));
+        let milestone = item["milestone"]["title"]
+            .as_str()
+            .map(|title| (Resource::Milestones, title.to_string()));
+        for key in labels.chain(milestone) {
+            *uses.entry(key).or_insert(0) += 1;
+        }
+    }
+    uses
+}
+
+/// Fetches a single (unpaginated) `resource` from the API, eg `metadata`,
+/// logging its progress tagged with the resource's path, eg `metadata`.
+async fn fetch_json(context: &RequestContext, resource: &str) -> Option<Value> {
+    let RequestContext {
+        logger,
+        limiter,
+        client,
+        api_base,
+        api_key,
+    } = context;
+    let id = resource.split('?').next().unwrap_or(resource);
+    with_permit(limiter, async {
+        logger.debug(id, format!("GET {resource}")).await;
+        let res = client
+            .get(format!("{api_base}/{resource}"))
+            .bearer_auth(api_key)
+            .send()
+            .await;
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                logger.debug(id, format!("unreachable: {err}")).await;
+                return None;
+            }
+        };
+        let status = res.status();
+        logger.debug(id, format!("{status}")).await;
+        res.json::<Value>().await.ok()
+    })
+    .await
+}
+
+/// Returns the `me` completion for the current `user`, whose token is used.
+///
+/// See: https://docs.gitlab.com/ee/api/users.html#for-non-administrator-users
+fn current_user_completion(user: &Value) -> Option<CompletionItemData> {
+    let username = user["username"].as_str()?;
+    Some(CompletionItemData {
+        completion: "me".to_string(),
+        description: Some(match user["name"].as_str() {
+            Some(name) => format!("Yourself, {name} (@{username})"),
+            None => format!("Yourself (@{username})"),
+        }),
+        snippet: None,
+        url: user["web_url"].as_str().map(str::to_string),
+    })
+}
+
+/// Detects the tier of the GitLab instance from its `metadata` and license,
+/// returning `None` if it can't be determined, eg because only admins can read
+/// the license.
+///
+/// See: https://docs.gitlab.com/ee/api/metadata.html
+async fn detect_tier(context: &RequestContext, metadata: Option<&Value>) -> Option<Tier> {
+    let metadata = metadata?;
+    let license = match metadata["enterprise"] {
+        Value::Bool(true) => fetch_json(context, "license").await,
+        _ => None,
+    };
+    tier_from_metadata(metadata, license.as_ref())
+}
+
+/// Returns the major and minor version of an instance with `metadata`, eg
+/// `(16, 5)` for `16.5.1-ee`.
+fn version_from_metadata(metadata: &Value) -> Option<(u32, u32)> {
+    let mut parts = metadata["version"].as_str()?.split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Returns the tier of an instance with `metadata`, and `license` (if it could
+/// be read).
+fn tier_from_metadata(metadata: &Value, license: Option<&Value>) -> Option<Tier> {
+    match (&metadata["enterprise"], license) {
+        // Community Edition only has the Free tier
+        (Value::Bool(false), _) => Some(Tier::Free),
+        // an Enterprise Edition instance without a license
+        (Value::Bool(true), Some(Value::Null)) => Some(Tier::Free),
+        (Value::Bool(true), Some(license)) => license["plan"].as_str().and_then(Tier::from_plan),
+        _ => None,
+    }
+}
+
+/// Fetches `url` and each page after it, merging them into a single array.
+/// `fetch_page` is given the number (from 1) and URL (or for GraphQL, cursor)
+/// of a page, and returns its JSON along with the URL of the next page, if any,
+/// or why it couldn't be fetched. An error for the first page is returned, as
+/// is an invalid token for any page, while another error for a later page stops
+/// fetching, keeping the pages before it.
+async fn fetch_all_pages<U, F, Fut>(
+    url: U,
+    mut fetch_page: F,
+) -> std::result::Result<Value, RequestError>
+where
+    F: FnMut(u32, U) -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<(Option<U>, Value), RequestError>>,
+{
+    let mut page = 1;
+    let (mut next_url, json) = fetch_page(page, url).await?;
+    let Value::Array(mut items) = json else {
+        // eg an error, which is reported as-is
+        return Ok(json);
+    };
+    // there's nothing more to fetch, whatever the headers say
+    if items.is_empty() {
+        return Ok(Value::Array(items));
+    }
+
+    while let Some(url) = next_url {
+        page += 1;
+        let (next, json) = match fetch_page(page, url).await {
+            Ok(page) => page,
+            Err(err @ RequestError::Status(reqwest::StatusCode::UNAUTHORIZED)) => return Err(err),
+            Err(_) => break,
+        };
+        match json {
+            Value::Array(page) if !page.is_empty() => items.extend(page),
+            _ => break,
+        }
+        next_url = next;
+    }
+
+    Ok(Value::Array(items))
+}
+
+/// Returns the contacts from a page of the GraphQL `group.contacts` query,
+/// along with the cursor of the next page, if any.
+///
+/// See: https://docs.gitlab.com/ee/api/graphql/reference/#customerrelationscontactconnection
+fn contacts_page(json: &Value) -> (Option<Value>, Value) {
+    let contacts = &json["data"]["group"]["contacts"];
+    let after = match &contacts["pageInfo"] {
+        page_info if page_info["hasNextPage"] == Value::Bool(true) => {
+            Some(page_info["endCursor"].clone()).filter(Value::is_string)
+        }
+        _ => None,
+    };
+    (after, contacts["nodes"].clone())
+}
+
+/// Returns the URL of the next page from a `Link` header, if any, eg
+/// `<https://gitlab.com/api/v4/...&page=2>; rel="next"`.
+///
+/// See: https://docs.gitlab.com/ee/api/rest/index.html#pagination-link-header
+fn next_page_url(link: &str) -> Option<String> {
+    link.split(',').find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        params
+            .split(';')
+            .any(|param| param.trim() == r#"rel="next""#)
+            .then(|| {
+                url.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    })
+}
+
+/// Whether `username` is that of one of GitLab's bot users, such as those
+/// created for project and group access tokens, eg `project_123_bot` or
+/// `group_45_bot_0123abcd`.
+fn is_bot_username(username: &str) -> bool {
+    let Some(rest) = username
+        .strip_prefix("project_")
+        .or_else(|| username.strip_prefix("group_"))
+    else {
+        return false;
+    };
+    let id_len = rest.chars().take_while(char::is_ascii_digit).count();
+    if id_len == 0 {
+        return false;
+    }
+    match rest[id_len..].strip_prefix("_bot") {
+        Some(suffix) => suffix.is_empty() || suffix.starts_with('_'),
+        None => false,
+    }
+}
+
+fn process_resource(
+    resource_kind: &Resource,
+    resources: Vec<Value>,
+    exclude_bots: bool,
+) -> HashSet<CompletionItemData> {
+    // boards are completed by the labels of their lists
+    let resources = match resource_kind {
+        Resource::Boards => resources
+            .iter()
+            .filter_map(|board| board["lists"].as_array())
+            .flatten()
+            .map(|list| list["label"].clone())
+            .collect(),
+        _ => resources,
+    };
+
+    resources
+        .into_iter()
+        .filter_map(|r| match r {
+            Value::Object(resource) => {
+                // https://docs.gitlab.com/ee/api/boards.html#list-project-issue-boards
+                // https://docs.gitlab.com/ee/api/branches.html#list-repository-branches
+                // https://docs.gitlab.com/ee/api/epics.html#list-epics-for-a-group
+                // https://docs.gitlab.com/ee/api/projects.html#list-a-projects-groups
+                // https://docs.gitlab.com/ee/api/issues.html#list-project-issues
+                // https://docs.gitlab.com/ee/api/labels.html#list-labels
+                // https://docs.gitlab.com/ee/api/milestones.html
+                // https://docs.gitlab.com/ee/api/members.html#list-all-members-of-a-group-or-project
+                // https://docs.gitlab.com/ee/api/projects.html#list-all-projects
+
+                let (gitlab_prefix, value_key, description_key) = match resource_kind {
+                    Resource::Boards => ("~", "name", "description"),
+                    Resource::Branches => ("", "name", "commit"),
+                    Resource::CiVariables => ("$", "key", "description"),
+                    Resource::Contacts => ("", "email", "firstName"),
+                    Resource::Epics => ("&", "iid", "title"),
+                    Resource::Groups => ("@", "full_path", "full_name"),
+                    Resource::Issues => ("#", "iid", "title"),
+                    Resource::Labels => ("~", "name", "description"),
+                    Resource::Members => ("@", "username", "name"),
+                    Resource::Milestones => {
+                        if let Value::Bool(true) = resource["expired"] {
+                            return None;
+                        }
+
+                        ("%", "title", "description")
+                    }
+                    Resource::Projects => ("", "path_with_namespace", "name"),
+                    Resource::DueDates
+                    | Resource::Emoji
+                    | Resource::HealthStatuses
+                    | Resource::QuickActions
+                    | Resource::Severities => unreachable!(),
+                };
+
+                // issues are referenced by their (numeric) iid
+                let completion = match &resource[value_key] {
+                    Value::String(completion) => completion.clone(),
+                    Value::Number(completion) => completion.to_string(),
+                    _ => return None,
+                };
+                if exclude_bots
+                    && *resource_kind == Resource::Members
+                    && is_bot_username(&completion)
+                {
+                    return None;
+                }
+                let description = match (resource_kind, &resource[description_key]) {
+                    (Resource::Contacts, Value::String(first_name)) => {
+                        match &resource["lastName"] {
+                            Value::String(last_name) => Some(format!("{first_name} {last_name}")),
+                            _ => Some(first_name.clone()),
+                        }
+                    }
+                    // describe branches by their latest commit
+                    (Resource::Branches, Value::Object(commit)) => commit
+                        .get("title")
+                        .and_then(Value::as_str)
+                        .map(str::to_string),
+                    (_, Value::String(description)) if !description.is_empty() => {
+                        Some(description.clone())
+                    }
+                    _ => None,
+                };
+                // note the label's colors, as shown in the GitLab UI
+                let description = match (
+                    resource_kind,
+                    resource.get("color"),
+                    resource.get("text_color"),
+                ) {
+                    (
+                        Resource::Labels,
+                        Some(Value::String(color)),
+                        Some(Value::String(text_color)),
+                    ) => {
+                        let colors = format!("Color: `{color}` with `{text_color}` text");
+                        match description {
+                            Some(description) => Some(format!("{description}\n\n{colors}")),
+                            None => Some(colors),
+                        }
+                    }
+                    _ => description,
+                };
+
+                let completion = match resource_kind {
+                    Resource::Contacts => format!("[contact:{completion}]"),
+                    _ if completion.contains([' ']) => {
+                        format!(r#"{gitlab_prefix}"{completion}""#)
+                    }
+                    // otherwise, eg `%13.0` would refer to the milestone with
+                    // ID 13
+                    Resource::Milestones
+                        if completion.starts_with(|c: char| c.is_ascii_digit()) =>
+                    {
+                        format!(r#"{gitlab_prefix}"{completion}""#)
+                    }
+                    _ => format!("{gitlab_prefix}{completion}"),
+                };
+
+                Some(CompletionItemData {
+                    completion,
+                    description,
+                    snippet: None,
+                    // eg a milestone's or user's page
+                    url: resource
+                        .get("web_url")
+                        .and_then(Value::as_str)
+                        .map(str::to_string),
+                })
+            }
+            Value::Null
+            | Value::Bool(_)
+            | Value::Number(_)
+            | Value::String(_)
+            | Value::Array(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_with_source(source: &str) -> LspState {
+        let mut state = LspState::default();
+        state
+            .sources
+            .insert("/test.md".to_string(), source.to_string());
+        state
+    }
+
+    fn position(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    /// The labels of the completions at `line` and `character` of `/test.md`.
+    fn completion_labels(state: &LspState, line: u32, character: u32) -> Vec<String> {
+        match completions_for_position(state, "/test.md", &position(line, character)) {
+            Some(CompletionResponse::Array(items)) => items.into_iter().map(|i| i.label).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// A service with `state`, whose messages to the client are discarded, as
+    /// the server blocks on them unless someone is reading them.
+    fn test_service(state: LspState) -> LspService<Lsp> {
+        let (service, socket) = LspService::new(|client| Lsp {
+            state: std::sync::Arc::new(Mutex::new(state)),
+            ..Lsp::new(client)
+        });
+        tokio::spawn(futures::StreamExt::for_each(socket, |_| async {}));
+        service
+    }
+
+    fn item(completion: &str, description: Option<&str>) -> CompletionItemData {
+        CompletionItemData {
+            completion: completion.to_string(),
+            description: description.map(str::to_string),
+            snippet: None,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn completion_with_empty_resource_is_none() {
+        let state = state_with_source("@");
+
+        assert_eq!(
+            completions_for_position(&state, "/test.md", &position(0, 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn completion_with_resource() {
+        let mut state = state_with_source("@");
+        state.members.insert(item("@user", Some("A User")));
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 1))
+        else {
+            panic!("expected completions");
+        };
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].label, "@user ");
+        assert_eq!(items[0].insert_text_mode, Some(InsertTextMode::AS_IS));
+        assert_eq!(
+            items[0].text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit {
+                range: Range {
+                    start: position(0, 0),
+                    end: position(0, 1),
+                },
+                new_text: "@user ".to_string(),
+            }))
+        );
+    }
+
+    #[test]
+    fn completion_of_issues_after_issue_quick_action() {
+        let mut state = state_with_source("/duplicate #\n# Heading\n/set_parent #");
+        state.issues.insert(item("#123", Some("An issue")));
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 12))
+        else {
+            panic!("expected completions");
+        };
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].label, "#123 ");
+
+        assert_eq!(
+            completions_for_position(&state, "/test.md", &position(1, 1)),
+            None
+        );
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(2, 13))
+        else {
+            panic!("expected completions");
+        };
+        assert_eq!(items[0].label, "#123 ");
+    }
+
+    #[test]
+    fn completion_of_parent_quick_actions() {
+        let mut state = state_with_source("/set_parent #\n/remove_parent #\n/re");
+        state.client_support.snippets = true;
+        state.config.document_context = Some(DocumentContext::IssueComment);
+        state.issues.insert(item("#123", Some("An issue")));
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 13))
+        else {
+            panic!("expected completions");
+        };
+        assert_eq!(items[0].label, "#123 ");
+
+        // removing the parent is standalone, so takes no reference
+        assert_eq!(
+            completions_for_position(&state, "/test.md", &position(1, 16)),
+            None
+        );
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(2, 3))
+        else {
+            panic!("expected completions");
+        };
+        let Some(remove_parent) = items.iter().find(|i| i.label == "/remove_parent ") else {
+            panic!("expected /remove_parent");
+        };
+        let Some(CompletionTextEdit::Edit(ref edit)) = remove_parent.text_edit else {
+            panic!("expected text edit");
+        };
+        assert_eq!(edit.new_text, "/remove_parent ");
+        assert_eq!(remove_parent.insert_text_format, None);
+
+        // there's no parent to remove from a new issue
+        state.config.document_context = Some(DocumentContext::Issue);
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(2, 3))
+        else {
+            panic!("expected completions");
+        };
+        assert!(!items.iter().any(|i| i.label == "/remove_parent "));
+    }
+
+    #[test]
+    fn nested_subgroup_project_from_remote_to_api_url() {
+        let config = Config {
+            project: git::project_from_remote_url(
+                "git@gitlab.com:group/subgroup/subsubgroup/project.git",
+            ),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.project.as_deref(),
+            Some("group/subgroup/subsubgroup/project")
+        );
+        assert_eq!(
+            gitlab_resource_url(
+                "https://gitlab.com/api/v4/",
+                config.project.as_deref().unwrap_or_default(),
+                config.members_scope,
+                &Resource::Labels
+            ),
+            "https://gitlab.com/api/v4/projects/group%2Fsubgroup%2Fsubsubgroup%2Fproject/labels?per_page=100"
+        );
+    }
+
+    #[test]
+    fn epics_from_project_group_api_url() {
+        assert_eq!(
+            gitlab_resource_url(
+                "https://gitlab.com/api/v4",
+                "group/subgroup/project",
+                MembersScope::default(),
+                &Resource::Epics
+            ),
+            "https://gitlab.com/api/v4/groups/group%2Fsubgroup/epics?state=opened&per_page=100"
+        );
+    }
+
+    #[test]
+    fn process_contacts() {
+        let contacts = process_resource(
+            &Resource::Contacts,
+            vec![json!({
+                "email": "alex@example.com",
+                "firstName": "Alex",
+                "lastName": "Smith",
+            })],
+            true,
+        );
+
+        assert_eq!(
+            contacts,
+            HashSet::from([item("[contact:alex@example.com]", Some("Alex Smith"))])
+        );
+    }
+
+    #[test]
+    fn completion_past_end_of_line() {
+        let mut state = state_with_source("~bug");
+        state.labels.insert(item("~bug", None));
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 10))
+        else {
+            panic!("expected completions");
+        };
+        assert_eq!(
+            items[0].text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit {
+                range: Range {
+                    start: position(0, 0),
+                    end: position(0, 4),
+                },
+                new_text: "~bug ".to_string(),
+            }))
+        );
+
+        let state = state_with_source("~bug ");
+        assert_eq!(
+            completions_for_position(&state, "/test.md", &position(0, 10)),
+            None
+        );
+    }
+
+    #[test]
+    fn quick_actions_for_merge_request_templates() {
+        let mut state = LspState::default();
+        for pathname in ["/test.md", "/.gitlab/merge_request_templates/Default.md"] {
+            state.sources.insert(pathname.to_string(), "/".to_string());
+        }
+        let labels =
+            |pathname: &str| match completions_for_position(&state, pathname, &position(0, 1)) {
+                Some(CompletionResponse::Array(items)) => {
+                    items.into_iter().map(|i| i.label).collect::<Vec<_>>()
+                }
+                _ => vec![],
+            };
+
+        let all = labels("/test.md");
+        assert!(all.contains(&"/draft ".to_string()));
+        assert!(all.contains(&"/due ".to_string()));
+
+        let merge_request = labels("/.gitlab/merge_request_templates/Default.md");
+        assert!(merge_request.contains(&"/draft ".to_string()));
+        assert!(merge_request.contains(&"/assign ".to_string()));
+        assert!(!merge_request.contains(&"/due ".to_string()));
+        assert!(merge_request.contains(&"/request_review ".to_string()));
+        assert!(!all.contains(&"/reassign_reviewer ".to_string()));
+    }
+
+    #[test]
+    fn completion_of_members_for_reviewers() {
+        let mut state = state_with_source("/request_review @\n/reassign_reviewer @sam @");
+        state.members = HashSet::from([item("@alex", Some("Alex")), item("@sam", Some("Sam"))]);
+
+        // @sam has already been mentioned on the second line
+        for (line, character, count) in [(0, 17, 2), (1, 25, 1)] {
+            let Some(CompletionResponse::Array(items)) =
+                completions_for_position(&state, "/test.md", &position(line, character))
+            else {
+                panic!("expected completions at {line}:{character}");
+            };
+            assert_eq!(items.len(), count);
+            assert!(items
+                .iter()
+                .all(|i| i.detail.as_deref() == Some("username")));
+        }
+    }
+
+    #[test]
+    fn quick_actions_for_document_context() {
+        let mut state = state_with_source("/");
+
+        let all = completion_labels(&state, 0, 1);
+        assert!(all.contains(&"/draft ".to_string()));
+        assert!(all.contains(&"/due ".to_string()));
+        assert!(!all.contains(&"/reopen ".to_string()));
+
+        state.config.document_context = Some(DocumentContext::Issue);
+        let issue = completion_labels(&state, 0, 1);
+        assert!(issue.contains(&"/due ".to_string()));
+        assert!(!issue.contains(&"/draft ".to_string()));
+        assert!(!issue.contains(&"/reopen ".to_string()));
+
+        state.config.document_context = Some(DocumentContext::Comment);
+        let comment = completion_labels(&state, 0, 1);
+        assert!(comment.contains(&"/reopen ".to_string()));
+        assert!(comment.contains(&"/close ".to_string()));
+        assert!(comment.contains(&"/promote ".to_string()));
+        assert!(!issue.contains(&"/promote ".to_string()));
+
+        // reviews are only offered on merge requests
+        assert!(!all.contains(&"/approve ".to_string()));
+        state.config.document_context = Some(DocumentContext::IssueComment);
+        let issue_comment = completion_labels(&state, 0, 1);
+        assert!(issue_comment.contains(&"/reopen ".to_string()));
+        assert!(!issue_comment.contains(&"/approve ".to_string()));
+        state.config.document_context = Some(DocumentContext::MergeRequestComment);
+        let merge_request_comment = completion_labels(&state, 0, 1);
+        assert!(merge_request_comment.contains(&"/approve ".to_string()));
+        assert!(merge_request_comment.contains(&"/unapprove ".to_string()));
+        assert!(!merge_request_comment.contains(&"/promote ".to_string()));
+        assert!(comment.contains(&"/draft ".to_string()));
+    }
+
+    #[test]
+    fn config_update() {
+        let mut config = Config::default();
+        config
+            .update(&json!({"project": "group/project", "log_level": "info"}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(config.project.as_deref(), Some("group/project"));
+        assert_eq!(config.log_level, LogLevel::Info);
+
+        let mut updated = config.clone();
+        updated
+            .update(&json!({"log_level": "debug"}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert!(updated.fetches_same_resources(&config));
+
+        updated
+            .update(&json!({"instance_url": "https://gitlab.example.com"}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert!(!updated.fetches_same_resources(&config));
+        assert_eq!(updated.api_base(), "https://gitlab.example.com/api/v4");
+
+        assert!(config.update(&json!({"members_scope": "some"})).is_err());
+    }
+
+    #[test]
+    fn rebuild_config_from_file_and_options() {
+        let root = std::env::temp_dir().join(format!("gitlab-lsp-config-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap_or_else(|err| panic!("{err}"));
+        let write = |contents: &str| {
+            std::fs::write(root.join(config_file::FILENAME), contents)
+                .unwrap_or_else(|err| panic!("{err}"))
+        };
+        let mut state = LspState {
+            workspace_root: Some(root.clone()),
+            detected_project: Some("detected/project".to_string()),
+            ..LspState::default()
+        };
+        state.config.api_key = Some("token".to_string());
+        let options = json!({"members_scope": "direct"});
+
+        write("project = \"group/project\"\nlog_level = \"error\"");
+        let config = state
+            .rebuild_config(&options)
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(config.project.as_deref(), Some("group/project"));
+        assert_eq!(config.log_level, LogLevel::Error);
+        assert_eq!(config.members_scope, MembersScope::Direct);
+        assert_eq!(config.api_key.as_deref(), Some("token"));
+
+        // settings removed from the file no longer apply
+        write("");
+        let config = state
+            .rebuild_config(&options)
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(config.project.as_deref(), Some("detected/project"));
+        assert_eq!(config.log_level, LogLevel::default());
+        assert_eq!(config.members_scope, MembersScope::Direct);
+
+        write("project =");
+        let err = state.rebuild_config(&options).err();
+        assert_eq!(
+            err.and_then(|err| err.data),
+            Some(json!({"kind": "invalid_config_file", "file": config_file::FILENAME}))
+        );
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn config_http_options() {
+        let mut config = Config::default();
+        assert_eq!(config.http, HttpOptions::default());
+        config
+            .update(&json!({"http": {
+                "http2_prior_knowledge": true,
+                "pool_max_idle_per_host": 4,
+                "tcp_keepalive_secs": 60,
+            }}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(
+            config.http,
+            HttpOptions {
+                http2_prior_knowledge: true,
+                pool_max_idle_per_host: Some(4),
+                tcp_keepalive_secs: Some(60),
+            }
+        );
+        assert!(config.http_client().is_ok());
+
+        for http in [
+            json!(true),
+            json!({"http2_prior_knowledge": "yes"}),
+            json!({"pool_max_idle_per_host": -1}),
+            json!({"tcp_keepalive_secs": 0}),
+            json!({"pool_size": 4}),
+        ] {
+            assert!(config.update(&json!({ "http": http })).is_err(), "{http}");
+        }
+    }
+
+    #[test]
+    fn config_rejects_empty_project() {
+        for project in ["", "  ", "https://gitlab.com/"] {
+            let mut config = Config::default();
+            let Err(err) = config.update(&json!({ "project": project })) else {
+                panic!("expected {project:?} to be rejected");
+            };
+            assert_eq!(
+                err.message,
+                "Error: required configuration param 'project' not supplied"
+            );
+            assert_eq!(
+                err.data,
+                Some(json!({"kind": "missing_param", "param": "project"}))
+            );
+            assert_eq!(config.project, None);
+        }
+    }
+
+    #[test]
+    fn api_base_from_instance_url() {
+        let api_base = |instance_url: &str| {
+            Config {
+                instance_url: Some(instance_url.to_string()),
+                ..Config::default()
+            }
+            .api_base()
+        };
+
+        assert_eq!(Config::default().api_base(), "https://gitlab.com/api/v4");
+        assert_eq!(
+            api_base("https://gitlab.example.com/"),
+            "https://gitlab.example.com/api/v4"
+        );
+        assert_eq!(
+            api_base("https://example.com/gitlab/api/v4/"),
+            "https://example.com/gitlab/api/v4"
+        );
+        assert_eq!(
+            api_base("https://gitlab.example.com/api/v3"),
+            "https://gitlab.example.com/api/v3"
+        );
+    }
+
+    #[test]
+    fn completion_of_quick_action_snippet() {
+        let mut state = state_with_source("/sh");
+        state.client_support.snippets = true;
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 3))
+        else {
+            panic!("expected completions");
+        };
+        let Some(shrug) = items.iter().find(|i| i.label == "/shrug ") else {
+            panic!("expected /shrug");
+        };
+        assert_eq!(shrug.insert_text_format, Some(InsertTextFormat::SNIPPET));
+        assert_eq!(
+            shrug.text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit {
+                range: Range {
+                    start: position(0, 0),
+                    end: position(0, 3),
+                },
+                new_text: "/shrug ${1:comment}".to_string(),
+            }))
+        );
+    }
+
+    #[test]
+    fn completion_of_quick_action_snippet_needs_client_support() {
+        let mut state = state_with_source("/as");
+        let completion = |state: &LspState| {
+            let Some(CompletionResponse::Array(items)) =
+                completions_for_position(state, "/test.md", &position(0, 3))
+            else {
+                panic!("expected completions");
+            };
+            let Some(assign) = items.into_iter().find(|i| i.label == "/assign ") else {
+                panic!("expected /assign");
+            };
+            let Some(CompletionTextEdit::Edit(edit)) = assign.text_edit else {
+                panic!("expected text edit");
+            };
+            (assign.insert_text_format, edit.new_text)
+        };
+
+        assert_eq!(completion(&state), (None, "/assign ".to_string()));
+
+        state.client_support.snippets = true;
+        assert_eq!(
+            completion(&state),
+            (
+                Some(InsertTextFormat::SNIPPET),
+                "/assign ${1:@user}".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn completion_filter_text_excludes_prefix() {
+        let mut state = state_with_source("~prio");
+        state.labels.insert(item("~priority::high", None));
+        state.labels.insert(item(r#"~"needs review""#, None));
+
+        let Some(CompletionResponse::Array(mut items)) =
+            completions_for_position(&state, "/test.md", &position(0, 5))
+        else {
+            panic!("expected completions");
+        };
+        items.sort_by(|a, b| a.label.cmp(&b.label));
+        assert_eq!(items[0].filter_text.as_deref(), Some("needs review"));
+        assert_eq!(items[1].filter_text.as_deref(), Some("priority::high"));
+    }
+
+    #[test]
+    fn completion_of_clone_arguments() {
+        let mut state = state_with_source("/clone gr\n/clone #\n/title gr");
+        state
+            .projects
+            .insert(item("group/project", Some("Project")));
+        state.issues.insert(item("#123", Some("An issue")));
+
+        assert_eq!(completion_labels(&state, 0, 9), vec!["group/project "]);
+        assert_eq!(completion_labels(&state, 1, 8), vec!["#123 "]);
+        assert!(completion_labels(&state, 2, 9).is_empty());
+    }
+
+    #[test]
+    fn process_labels_with_colors() {
+        let labels = process_resource(
+            &Resource::Labels,
+            vec![
+                json!({
+                    "name": "bug",
+                    "description": "Something isn't working",
+                    "color": "#d9534f",
+                    "text_color": "#FFFFFF",
+                }),
+                json!({
+                    "name": "needs review",
+                    "description": null,
+                    "color": "#f0ad4e",
+                    "text_color": "#333333",
+                }),
+            ],
+            true,
+        );
+
+        assert_eq!(
+            labels,
+            HashSet::from([
+                item(
+                    "~bug",
+                    Some("Something isn't working\n\nColor: `#d9534f` with `#FFFFFF` text")
+                ),
+                item(
+                    r#"~"needs review""#,
+                    Some("Color: `#f0ad4e` with `#333333` text")
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn label_locations_across_documents() {
+        let mut state = LspState::default();
+        state.sources.insert(
+            "/one.md".to_string(),
+            "~bug ~bugfix\n/label ~\"bug\"".to_string(),
+        );
+        state
+            .sources
+            .insert("/two.md".to_string(), "Also a 🐛 ~bug".to_string());
+
+        let Some(label) = label_at_position(&state, "/one.md", &position(1, 9)) else {
+            panic!("expected label");
+        };
+        assert_eq!(label.name, "bug");
+
+        let locations = label_locations(&state, &label.name)
+            .into_iter()
+            .map(|(uri, range)| (uri.path().to_string(), range))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            locations,
+            vec![
+                (
+                    "/one.md".to_string(),
+                    Range {
+                        start: position(0, 0),
+                        end: position(0, 4)
+                    }
+                ),
+                (
+                    "/one.md".to_string(),
+                    Range {
+                        start: position(1, 7),
+                        end: position(1, 13)
+                    }
+                ),
+                (
+                    "/two.md".to_string(),
+                    // `🐛` is 2 UTF-16 code units
+                    Range {
+                        start: position(0, 10),
+                        end: position(0, 14)
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn completion_documentation_format() {
+        let mut state = state_with_source("~");
+        state.labels = HashSet::from([item("~bug", Some("Something's **broken**"))]);
+        let documentation = |state: &LspState| {
+            let Some(CompletionResponse::Array(items)) =
+                completions_for_position(state, "/test.md", &position(0, 1))
+            else {
+                panic!("expected completions");
+            };
+            items.into_iter().next().and_then(|item| item.documentation)
+        };
+
+        assert_eq!(
+            documentation(&state),
+            Some(Documentation::String("Something's **broken**".to_string()))
+        );
+
+        state.client_support = ClientSupport::from_capabilities(&ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                completion: Some(CompletionClientCapabilities {
+                    completion_item: Some(CompletionItemCapability {
+                        documentation_format: Some(vec![
+                            MarkupKind::Markdown,
+                            MarkupKind::PlainText,
+                        ]),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            documentation(&state),
+            Some(Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: "Something's **broken**".to_string(),
+            }))
+        );
+    }
+
+    #[test]
+    fn completion_of_create_merge_request() {
+        let mut state = state_with_source(
+            "/title Fix the **broken** thing\n/create_merge_request fe\n/create_me",
+        );
+        state.client_support.snippets = true;
+        state.branches = HashSet::from([item("feature/thing", Some("Add the thing"))]);
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(1, 24))
+        else {
+            panic!("expected branches");
+        };
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].label, "feature/thing ");
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(2, 10))
+        else {
+            panic!("expected quick actions");
+        };
+        let Some(item) = items
+            .into_iter()
+            .find(|i| i.label == "/create_merge_request ")
+        else {
+            panic!("expected /create_merge_request");
+        };
+        let Some(CompletionTextEdit::Edit(edit)) = item.text_edit else {
+            panic!("expected text edit");
+        };
+        assert_eq!(
+            edit.new_text,
+            "/create_merge_request ${1:fix-the-broken-thing}"
+        );
+    }
+
+    #[test]
+    fn completion_of_scoped_labels_sorts_by_scope() {
+        let mut state = state_with_source("~");
+        state.labels = HashSet::from([
+            item("~priority::high", None),
+            item("~priority-queue", None),
+            item("~priority::low", None),
+        ]);
+
+        let Some(CompletionResponse::Array(mut items)) =
+            completions_for_position(&state, "/test.md", &position(0, 1))
+        else {
+            panic!("expected completions");
+        };
+        items.sort_by(|a, b| a.sort_text.cmp(&b.sort_text));
+
+        assert_eq!(
+            items
+                .iter()
+                .map(|i| i.sort_text.as_deref().unwrap_or_default())
+                .collect::<Vec<_>>(),
+            vec!["priority-queue", "priority/high", "priority/low"]
+        );
+    }
+
+    #[test]
+    fn label_patterns() {
+        assert!(label_matches("bot::", "bot::triage"));
+        assert!(!label_matches("bot::", "robot::triage"));
+        assert!(label_matches("bot::*", "bot::triage"));
+        assert!(label_matches("*::high", "priority::high"));
+        assert!(!label_matches("*::high", "priority::higher"));
+        assert!(label_matches("type::*::ui", "type::bug::ui"));
+        assert!(!label_matches("type::*::ui", "type::bug"));
+        assert!(label_matches("*", "anything"));
+
+        let mut config = Config::default();
+        config
+            .update(&json!({
+                "label_include": ["priority::", "type::*"],
+                "label_exclude": ["*::wontfix"],
+            }))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert!(config.offers_label("priority::high"));
+        assert!(config.offers_label("type::bug"));
+        assert!(!config.offers_label("type::wontfix"));
+        assert!(!config.offers_label("bot::triage"));
+
+        assert!(config.update(&json!({"label_exclude": "bot::*"})).is_err());
+    }
+
+    #[test]
+    fn hover_for_quick_actions_and_references() {
+        let mut state = state_with_source("  /assign @alex\n/label ~bug ~unknown");
+        state.members.insert(item("@alex", Some("Alex Example")));
+        state
+            .labels
+            .insert(item("~bug", Some("Something isn't working")));
+        let hover = |line, character| {
+            hover_for_position(&state, "/test.md", &position(line, character)).map(|hover| {
+                let HoverContents::Markup(contents) = hover.contents else {
+                    panic!("expected markup");
+                };
+                (contents.value, hover.range)
+            })
+        };
+
+        let Some((contents, range)) = hover(0, 4) else {
+            panic!("expected hover for /assign");
+        };
+        assert!(contents.starts_with("`/assign @user`\n\nAssign users\n\n"));
+        assert!(contents.ends_with(
+            "[GitLab docs](https://docs.gitlab.com/ee/user/project/quick_actions.html)"
+        ));
+        assert_eq!(
+            range,
+            Some(Range {
+                start: position(0, 2),
+                end: position(0, 9),
+            })
+        );
+
+        assert_eq!(
+            hover(0, 12).map(|(contents, _)| contents),
+            Some("Alex Example".to_string())
+        );
+        assert_eq!(
+            hover(1, 9).map(|(contents, _)| contents),
+            Some("Something isn't working".to_string())
+        );
+        assert_eq!(hover(1, 14), None);
+    }
+
+    #[test]
+    fn normalize_pasted_projects() {
+        for project in [
+            "group/subgroup/project",
+            "/group/subgroup/project",
+            "group/subgroup/project/",
+            "group/subgroup/project.git",
+            " group/subgroup/project ",
+            "https://gitlab.com/group/subgroup/project",
+            "https://gitlab.example.com/group/subgroup/project.git",
+            "https://gitlab.com/group/subgroup/project/-/issues/123",
+        ] {
+            assert_eq!(
+                normalize_project(project),
+                "group/subgroup/project",
+                "{project}"
+            );
+        }
+    }
+
+    #[test]
+    fn dump_state_redacts_token() {
+        let mut state = LspState::default();
+        state.config.api_key = Some("glpat-secret".to_string());
+        state.config.project = Some("group/project".to_string());
+        state
+            .labels
+            .insert(item("~bug", Some("Something isn't working")));
+
+        let dump = dump_state(&state);
+        assert!(!dump.to_string().contains("glpat-secret"));
+        assert_eq!(dump["config"]["api_key"], json!("<redacted>"));
+        assert_eq!(dump["config"]["project"], json!("group/project"));
+        assert_eq!(
+            dump["labels"],
+            json!([{"completion": "~bug", "description": "Something isn't working"}])
+        );
+    }
+
+    #[test]
+    fn next_page_from_link_header() {
+        let link = r#"<https://gitlab.com/api/v4/projects/1/members/all?page=2&per_page=100>; rel="next", <https://gitlab.com/api/v4/projects/1/members/all?page=1&per_page=100>; rel="first""#;
+        assert_eq!(
+            next_page_url(link).as_deref(),
+            Some("https://gitlab.com/api/v4/projects/1/members/all?page=2&per_page=100")
+        );
+        assert_eq!(
+            next_page_url(
+                r#"<https://gitlab.com/api/v4/projects/1/members/all?page=1>; rel="first""#
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_all_pages_follows_next_links() {
+        let pages = HashMap::from([
+            (
+                "page1".to_string(),
+                (Some("page2".to_string()), json!([{"username": "alex"}])),
+            ),
+            ("page2".to_string(), (None, json!([{"username": "sam"}]))),
+        ]);
+        let mut fetched = vec![];
+
+        let json = fetch_all_pages("page1".to_string(), |page, url| {
+            fetched.push((page, url.clone()));
+            let page = pages.get(&url).cloned().unwrap_or((None, Value::Null));
+            async move { Ok(page) }
+        })
+        .await;
+
+        assert_eq!(
+            fetched,
+            vec![(1, "page1".to_string()), (2, "page2".to_string())]
+        );
+        assert_eq!(json, Ok(json!([{"username": "alex"}, {"username": "sam"}])));
+    }
+
+    #[tokio::test]
+    async fn fetch_all_pages_with_error_status() {
+        // eg an expired token
+        let unauthorized = || RequestError::Status(reqwest::StatusCode::UNAUTHORIZED);
+        let json = fetch_all_pages("page1".to_string(), |_page, _url| async {
+            Err(unauthorized())
+        })
+        .await;
+        assert_eq!(json, Err(unauthorized()));
+
+        // the pages fetched before an error are kept
+        let later_page_error = |err: fn() -> RequestError| {
+            fetch_all_pages("page1".to_string(), move |page, _url| async move {
+                match page {
+                    1 => Ok((Some("page2".to_string()), json!([{"username": "alex"}]))),
+                    _ => Err(err()),
+                }
+            })
+        };
+        assert_eq!(
+            later_page_error(|| RequestError::Status(reqwest::StatusCode::BAD_GATEWAY)).await,
+            Ok(json!([{"username": "alex"}]))
+        );
+        assert_eq!(
+            later_page_error(|| RequestError::InvalidJson).await,
+            Ok(json!([{"username": "alex"}]))
+        );
+        // unless the token has expired meanwhile
+        assert_eq!(
+            later_page_error(|| RequestError::Status(reqwest::StatusCode::UNAUTHORIZED)).await,
+            Err(unauthorized())
+        );
+
+        let err = token_invalid_error();
+        assert_eq!(err.data, Some(json!({"kind": "token_invalid"})));
+    }
+
+    #[test]
+    fn expand_env_vars() {
+        let lookup = |var: &str| match var {
+            "CI_PROJECT_PATH" => Some("group/project".to_string()),
+            "CI_SERVER_HOST" => Some("gitlab.example.com".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(
+            expand_env("${CI_PROJECT_PATH}", lookup),
+            Ok("group/project".to_string())
+        );
+        assert_eq!(
+            expand_env("https://${CI_SERVER_HOST}/${CI_PROJECT_PATH}", lookup),
+            Ok("https://gitlab.example.com/group/project".to_string())
+        );
+        assert_eq!(
+            expand_env("no/vars/$HOME/{here}", lookup),
+            Ok("no/vars/$HOME/{here}".to_string())
+        );
+        assert_eq!(
+            expand_env("${UNSET}/project", lookup),
+            Err("UNSET".to_string())
+        );
+    }
+
+    #[test]
+    fn completion_of_quoted_label_wrapped_onto_next_line() {
+        let mut state = state_with_source("/label ~bug ~\"needs\nrevi\nrevi");
+        state.labels = HashSet::from([item("~bug", None), item("~\"needs review\"", None)]);
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(1, 4))
+        else {
+            panic!("expected completions");
+        };
+        // `~bug` is already applied by the quick action
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            items[0].text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit {
+                range: range((0, 12), (1, 4)),
+                new_text: "~\"needs review\" ".to_string(),
+            }))
+        );
+
+        // unless the previous line ends within a quoted reference
+        assert_eq!(
+            completions_for_position(&state, "/test.md", &position(2, 4)),
+            None
+        );
+
+        assert_eq!(open_quoted_reference("/label ~\"needs"), Some(7));
+        assert_eq!(open_quoted_reference("%\"Sprint"), Some(0));
+        assert_eq!(open_quoted_reference("/label ~\"needs review\""), None);
+        assert_eq!(open_quoted_reference("say \"hello"), None);
+    }
+
+    #[test]
+    fn completion_of_members_excludes_mentioned_members() {
+        let mut state = state_with_source("/assign @alex @Sam @\ncc @alex @\n/assign @");
+        state.members = HashSet::from([item("@alex", None), item("@sam", None), item("@jo", None)]);
+        let members = |line, character| {
+            let mut members = completion_labels(&state, line, character);
+            members.sort();
+            members
+        };
+
+        assert_eq!(members(0, 20), vec!["@jo "]);
+        // the member being typed is still offered
+        assert_eq!(members(0, 9), vec!["@alex ", "@jo "]);
+        // mentions outside of quick actions aren't filtered
+        assert_eq!(members(1, 10), vec!["@alex ", "@jo ", "@sam "]);
+        assert_eq!(members(2, 9), vec!["@alex ", "@jo ", "@sam "]);
+    }
+
+    #[test]
+    fn completion_of_labels_excludes_applied_labels() {
+        let mut state = state_with_source(
+            "/label ~bug ~\"needs review\" ~\nSee ~bug and ~\n/relabel ~urgent ~",
+        );
+        state.labels = HashSet::from([
+            item("~bug", None),
+            item("~\"needs review\"", None),
+            item("~urgent", None),
+        ]);
+        let labels = |line, character| {
+            let mut labels = completion_labels(&state, line, character);
+            labels.sort();
+            labels
+        };
+
+        assert_eq!(labels(0, 29), vec!["~urgent "]);
+        // the label being typed is still offered
+        assert_eq!(labels(0, 9), vec!["~bug ", "~urgent "]);
+        // as are labels outside of quick actions
+        assert_eq!(labels(1, 14).len(), 3);
+        assert_eq!(labels(2, 18), vec!["~\"needs review\" ", "~bug "]);
+    }
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Range {
+        Range {
+            start: position(start.0, start.1),
+            end: position(end.0, end.1),
+        }
+    }
+
+    #[test]
+    fn apply_change_with_multibyte_content() {
+        // `é` is 2 bytes but 1 UTF-16 unit, `🐛` is 4 bytes and 2 UTF-16 units
+        let mut source = "café 🐛 ~bug\n".to_string();
+        apply_change(&mut source, range((0, 8), (0, 12)), "~defect");
+        assert_eq!(source, "café 🐛 ~defect\n");
+
+        apply_change(&mut source, range((0, 5), (0, 7)), "🦋");
+        assert_eq!(source, "café 🦋 ~defect\n");
+
+        // inserting at, and past, the end of the document
+        apply_change(&mut source, range((1, 0), (1, 0)), "/label ");
+        apply_change(&mut source, range((5, 0), (5, 3)), "~bug");
+        assert_eq!(source, "café 🦋 ~defect\n/label ~bug");
+    }
+
+    #[test]
+    fn apply_change_spanning_lines() {
+        let mut source = "# Título\r\n\n/assign @alex\n/label ~bug\n".to_string();
+        apply_change(&mut source, range((0, 4), (2, 8)), "ok\n/assign ");
+        assert_eq!(source, "# Tíok\n/assign @alex\n/label ~bug\n");
+
+        // deleting whole lines, past the end of a line
+        apply_change(&mut source, range((1, 0), (2, 99)), "");
+        assert_eq!(source, "# Tíok\n\n");
+    }
+
+    #[test]
+    fn completion_of_members_and_groups() {
+        let mut state = state_with_source("@");
+        state.members.insert(item("@alex", Some("Alex Example")));
+        state
+            .groups
+            .insert(item("@group/backend-team", Some("Group / Backend Team")));
+
+        let Some(CompletionResponse::Array(mut items)) =
+            completions_for_position(&state, "/test.md", &position(0, 1))
+        else {
+            panic!("expected completions");
+        };
+        items.sort_by(|a, b| a.label.cmp(&b.label));
+
+        assert_eq!(
+            items
+                .iter()
+                .map(|i| (i.label.as_str(), i.detail.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("@alex ", Some("username")),
+                ("@group/backend-team ", Some("group")),
+            ]
+        );
+    }
+
+    #[test]
+    fn contacts_page_with_next_cursor() {
+        let page = |has_next_page| {
+            json!({"data": {"group": {"contacts": {
+                "nodes": [{"email": "alex@example.com", "firstName": "Alex", "lastName": null}],
+                "pageInfo": {"hasNextPage": has_next_page, "endCursor": "eyJpZCI6IjEifQ"},
+            }}}})
+        };
+        let nodes = json!([{"email": "alex@example.com", "firstName": "Alex", "lastName": null}]);
+
+        assert_eq!(
+            contacts_page(&page(true)),
+            (Some(json!("eyJpZCI6IjEifQ")), nodes.clone())
+        );
+        assert_eq!(contacts_page(&page(false)), (None, nodes));
+        // eg the group doesn't exist
+        assert_eq!(
+            contacts_page(&json!({"data": {"group": null}})),
+            (None, Value::Null)
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_all_pages_of_empty_resource() {
+        let mut fetched = vec![];
+
+        // eg a project with no labels, where the headers still point at a
+        // next page
+        let json = fetch_all_pages("page1".to_string(), |_page, url| {
+            fetched.push(url);
+            async { Ok((Some("page2".to_string()), json!([]))) }
+        })
+        .await;
+
+        assert_eq!(fetched, vec!["page1"]);
+        assert_eq!(json, Ok(json!([])));
+        let Ok(Value::Array(labels)) = json else {
+            panic!("expected an array");
+        };
+        assert!(process_resource(&Resource::Labels, labels, true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_completion_releases_state() {
+        let service = test_service(state_with_source("~"));
+        let lsp = service.inner();
+        let params = CompletionParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: Url::parse("file:///test.md").unwrap_or_else(|err| panic!("{err}")),
+                },
+                position: position(0, 1),
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+            context: None,
+        };
+
+        // the request is still pending (eg waiting for the state) when it's
+        // cancelled, which tower-lsp does by dropping it
+        let state = lsp.state.lock().await;
+        let mut completion = Box::pin(lsp.completion(params.clone()));
+        assert!(futures::poll!(&mut completion).is_pending());
+        drop(completion);
+        drop(state);
+
+        assert!(lsp.state.try_lock().is_ok());
+        assert!(matches!(lsp.completion(params).await, Ok(None)));
+    }
+
+    #[test]
+    fn disabling_diagnostics_clears_them() {
+        let mut state = state_with_source("/asign @alex");
+        let mut config = state.config.clone();
+        config.diagnose_quick_actions = true;
+        assert_eq!(state.undiagnosed_documents(&config), vec![]);
+
+        state.config.diagnose_quick_actions = true;
+        assert_eq!(state.undiagnosed_documents(&config), vec![]);
+
+        config.diagnose_quick_actions = false;
+        assert_eq!(
+            state.undiagnosed_documents(&config),
+            vec![Url::parse("file:///test.md").unwrap_or_else(|err| panic!("{err}"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn config_change_refetches_without_holding_state() {
+        // GitLab never responds, so the refetch is pending until it's dropped
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0").unwrap_or_else(|err| panic!("{err}"));
+        let addr = listener.local_addr().unwrap_or_else(|err| panic!("{err}"));
+        let mut state = state_with_source("~");
+        state.config.api_key = Some("token".to_string());
+        state.client_options = json!({
+            "instance_url": format!("http://{addr}"),
+            "project": "group/project",
+        });
+        let service = test_service(state);
+        let lsp = service.inner();
+
+        let mut change = Box::pin(lsp.did_change_configuration(DidChangeConfigurationParams {
+            settings: json!({"members_scope": "direct"}),
+        }));
+        for _ in 0..10 {
+            assert!(futures::poll!(&mut change).is_pending());
+            tokio::task::yield_now().await;
+        }
+
+        let Ok(state) = lsp.state.try_lock() else {
+            panic!("expected the state to be released while refetching");
+        };
+        assert_eq!(state.config.members_scope, MembersScope::Direct);
+    }
+
+    #[tokio::test]
+    async fn completion_does_not_wait_for_lazy_resources() {
+        // GitLab never responds, so the lazy members are still being fetched
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0").unwrap_or_else(|err| panic!("{err}"));
+        let addr = listener.local_addr().unwrap_or_else(|err| panic!("{err}"));
+        let mut state = state_with_source("@al");
+        state
+            .config
+            .update(&json!({
+                "instance_url": format!("http://{addr}"),
+                "project": "group/project",
+                "load": {"members": "lazy"},
+            }))
+            .unwrap_or_else(|err| panic!("{err}"));
+        state.config.api_key = Some("token".to_string());
+        state.groups.insert(item("@alpha-group", None));
+        let service = test_service(state);
+        let lsp = service.inner();
+        let params = CompletionParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: Url::parse("file:///test.md").unwrap_or_else(|err| panic!("{err}")),
+                },
+                position: position(0, 3),
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+            context: None,
+        };
+
+        // what's already been fetched is offered, and asked for again later
+        let Ok(Some(CompletionResponse::List(list))) = lsp.completion(params).await else {
+            panic!("expected an incomplete list");
+        };
+        assert!(list.is_incomplete);
+        assert_eq!(
+            list.items
+                .iter()
+                .map(|item| item.label.as_str())
+                .collect::<Vec<_>>(),
+            vec!["@alpha-group "]
+        );
+        assert!(lsp.state.lock().await.loading.contains(&Resource::Members));
+    }
+
+    #[test]
+    fn completion_range_covers_partial_reference() {
+        let mut state = LspState::default();
+        state.labels.insert(item("~bug", None));
+
+        for (source, character, start, end) in [
+            ("~", 1, 0, 1),
+            ("~b", 2, 0, 2),
+            ("~bug", 4, 0, 4),
+            // the cursor may be anywhere in the reference
+            ("~bug", 2, 0, 4),
+            ("See ~b and", 6, 4, 6),
+            // positions are in UTF-16 code units, `é` is 1 and `🐛` is 2
+            ("café ~b", 7, 5, 7),
+            ("🐛 ~bu", 6, 3, 6),
+        ] {
+            state
+                .sources
+                .insert("/test.md".to_string(), source.to_string());
+            let Some(CompletionResponse::Array(items)) =
+                completions_for_position(&state, "/test.md", &position(0, character))
+            else {
+                panic!("expected completions for {source:?}");
+            };
+            assert_eq!(
+                items[0].text_edit,
+                Some(CompletionTextEdit::Edit(TextEdit {
+                    range: Range {
+                        start: position(0, start),
+                        end: position(0, end),
+                    },
+                    new_text: "~bug ".to_string(),
+                })),
+                "{source:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn completion_of_numeric_milestones() {
+        let milestones = process_resource(
+            &Resource::Milestones,
+            vec![
+                json!({"title": "13.0", "description": "Release 13.0", "expired": false}),
+                json!({"title": "v13.1", "description": "", "expired": false}),
+            ],
+            true,
+        );
+        let mut completions: Vec<&str> = milestones
+            .iter()
+            .map(|milestone| milestone.completion.as_str())
+            .collect();
+        completions.sort();
+        assert_eq!(completions, vec!["%\"13.0\"", "%v13.1"]);
+
+        let mut state = state_with_source("/milestone %13");
+        state.milestones = milestones;
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 14))
+        else {
+            panic!("expected completions");
+        };
+        let Some(item) = items.iter().find(|i| i.label == "%\"13.0\" ") else {
+            panic!("expected %\"13.0\"");
+        };
+        assert_eq!(item.filter_text.as_deref(), Some("13.0"));
+        assert_eq!(
+            item.text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit {
+                range: Range {
+                    start: position(0, 11),
+                    end: position(0, 14),
+                },
+                new_text: "%\"13.0\" ".to_string(),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn ping_before_resources_are_loaded() {
+        let (service, _socket) = LspService::new(Lsp::new);
+
+        let ping = service
+            .inner()
+            .ping()
+            .await
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(ping["resources_loaded"], json!(false));
+        assert!(ping["uptime_secs"].is_u64());
+    }
+
+    /// Serves canned GitLab API responses on a local port, for tests which
+    /// drive the server end to end. Each response is for any path containing
+    /// its pattern; anything else gets an empty list. Returns the instance URL.
+    fn mock_gitlab(responses: &'static [(&'static str, &'static str)]) -> String {
+        use std::io::{BufRead, Read, Write};
+
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0").unwrap_or_else(|err| panic!("{err}"));
+        let addr = listener.local_addr().unwrap_or_else(|err| panic!("{err}"));
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let path = {
+                    let mut reader = std::io::BufReader::new(&stream);
+                    let mut request_line = String::new();
+                    let _ = reader.read_line(&mut request_line);
+                    let mut content_length = 0;
+                    loop {
+                        let mut header = String::new();
+                        if reader.read_line(&mut header).unwrap_or(0) == 0 || header == "\r\n" {
+                            break;
+                        }
+                        if let Some((name, value)) = header.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap_or(0);
+                            }
+                        }
+                    }
+                    // eg the GraphQL query, which must be read before replying
+                    let _ = reader.read_exact(&mut vec![0; content_length]);
+                    request_line.split(' ').nth(1).unwrap_or("").to_string()
+                };
+                let body = responses
+                    .iter()
+                    .find(|(pattern, _)| path.contains(pattern))
+                    .map_or("[]", |(_, body)| body);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn smoke_test_lsp_lifecycle() {
+        let instance_url = mock_gitlab(&[
+            (
+                "/metadata",
+                r#"{"version": "16.11.0", "enterprise": false}"#,
+            ),
+            (
+                "/labels",
+                r##"[{"id": 1, "name": "bug", "description": "Something is broken", "color": "#d9534f"}]"##,
+            ),
+        ]);
+        // rather than a `token_command`, which would need a shell
+        let mut state = LspState::default();
+        state.config.api_key = Some("test-token".to_string());
+        let service = test_service(state);
+        let lsp = service.inner();
+
+        let init = lsp
+            .initialize(InitializeParams {
+                initialization_options: Some(json!({
+                    "instance_url": instance_url,
+                    "project": "group/project",
+                })),
+                ..InitializeParams::default()
+            })
+            .await
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert!(init.capabilities.completion_provider.is_some());
+        lsp.initialized(InitializedParams {}).await;
+        let ping = lsp.ping().await.unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(ping["resources_loaded"], json!(true));
+
+        let uri = Url::parse("file:///smoke.md").unwrap_or_else(|err| panic!("{err}"));
+        lsp.did_open(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: uri.clone(),
+                language_id: "markdown".to_string(),
+                version: 1,
+                text: "/label ~b".to_string(),
+            },
+        })
+        .await;
+        let completion = lsp
+            .completion(CompletionParams {
+                text_document_position: TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier { uri },
+                    position: position(0, 9),
+                },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
+            })
+            .await
+            .unwrap_or_else(|err| panic!("{err}"));
+        let Some(CompletionResponse::Array(items)) = completion else {
+            panic!("expected completions");
+        };
+        let Some(bug) = items.iter().find(|item| item.label == "~bug ") else {
+            panic!("expected ~bug in {items:?}");
+        };
+        assert_eq!(
+            bug.documentation,
+            Some(Documentation::String("Something is broken".to_string()))
+        );
+
+        lsp.shutdown().await.unwrap_or_else(|err| panic!("{err}"));
+    }
+
+    #[tokio::test]
+    async fn fetch_with_invalid_json() {
+        // eg the login page of a proxy in front of the instance
+        let instance_url = mock_gitlab(&[
+            ("/labels", "<html>Please log in</html>"),
+            (
+                "/milestones",
+                r#"[{"title": "v1", "description": null, "expired": false}]"#,
+            ),
+        ]);
+        let service = test_service(LspState::default());
+        let mut config = Config::default();
+        config
+            .update(&json!({"instance_url": instance_url, "project": "group/project"}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        config.api_key = Some("token".to_string());
+
+        let Ok(fetched) = service
+            .inner()
+            .fetch(&config, None, vec![Resource::Labels, Resource::Milestones])
+            .await
+        else {
+            panic!("expected the other resources to be fetched");
+        };
+        assert_eq!(
+            fetched
+                .resources
+                .iter()
+                .map(|(resource_kind, items)| (*resource_kind, items.len()))
+                .collect::<Vec<_>>(),
+            vec![(Resource::Milestones, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_resources_while_offline() {
+        // nothing is listening once the listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap_or_else(|err| panic!("{err}"));
+        let service = test_service(LspState::default());
+        let unreachable = |instance_url: String| {
+            let lsp = service.inner();
+            async move {
+                let mut config = Config::default();
+                config
+                    .update(&json!({"instance_url": instance_url, "project": "group/project"}))
+                    .unwrap_or_else(|err| panic!("{err}"));
+                config.api_key = Some("token".to_string());
+                match lsp.fetch_resources(&config).await {
+                    Ok(Some(refetched)) => refetched.unreachable,
+                    _ => panic!("expected to fetch resources"),
+                }
+            }
+        };
+
+        assert!(unreachable(format!("http://{addr}")).await);
+        // GitLab responding with errors (or nothing) isn't being offline
+        assert!(!unreachable(mock_gitlab(&[("/labels", "<html>Please log in</html>")])).await);
+    }
+
+    #[tokio::test]
+    async fn refresh_once_gitlab_is_reachable() {
+        let instance_url = mock_gitlab(&[(
+            "/labels",
+            r#"[{"id": 1, "name": "bug", "description": null}]"#,
+        )]);
+        let mut state = LspState::default();
+        state
+            .config
+            .update(&json!({"instance_url": instance_url, "project": "group/project"}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        state.config.api_key = Some("token".to_string());
+        // eg GitLab couldn't be reached when the server started
+        state.unreachable = true;
+        let service = test_service(state);
+        let lsp = service.inner();
+
+        let counts = lsp
+            .execute_command(ExecuteCommandParams {
+                command: REFRESH_COMMAND.to_string(),
+                arguments: vec![],
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(
+            counts.map(|counts| counts["labels"].clone()),
+            Some(json!(1))
+        );
+        assert!(!lsp.state.lock().await.unreachable);
+    }
+
+    #[tokio::test]
+    async fn fetch_resources_detects_service_desk() {
+        let instance_url = mock_gitlab(&[
+            // the project's resources, eg its contacts
+            ("/projects/group%2Fproject/", "[]"),
+            (
+                "/projects/group%2Fproject",
+                r#"{"id": 1, "service_desk_enabled": true}"#,
+            ),
+        ]);
+        let service = test_service(LspState::default());
+        let lsp = service.inner();
+        let service_desk = |resources: Value| {
+            let instance_url = instance_url.clone();
+            async move {
+                let mut config = Config::default();
+                config
+                    .update(&json!({
+                        "instance_url": instance_url,
+                        "project": "group/project",
+                        "resources": resources,
+                    }))
+                    .unwrap_or_else(|err| panic!("{err}"));
+                config.api_key = Some("token".to_string());
+                match lsp.fetch_resources(&config).await {
+                    Ok(Some(refetched)) => refetched.service_desk,
+                    _ => panic!("expected to fetch resources"),
+                }
+            }
+        };
+
+        // the state isn't needed (or held) while fetching
+        let _state = lsp.state.lock().await;
+        assert!(service_desk(json!(["contacts"])).await);
+        // it's only checked when contacts are enabled
+        assert!(!service_desk(json!([])).await);
+    }
+
+    #[test]
+    fn inlay_hints_for_issues() {
+        let mut state = state_with_source("# Heading\n/relate #123 #456\nSee #123 and #789\n#123");
+        state.issues = HashSet::from([
+            item("#123", Some("Fix the thing")),
+            item(
+                "#456",
+                Some("A very long title which goes on and on and on"),
+            ),
+        ]);
+
+        let hints = inlay_hints(
+            &state,
+            "/test.md",
+            &Range {
+                start: position(0, 0),
+                end: position(2, 0),
+            },
+        );
+        assert_eq!(
+            hints
+                .iter()
+                .map(|hint| {
+                    let InlayHintLabel::String(ref label) = hint.label else {
+                        panic!("expected a plain label: {hint:?}");
+                    };
+                    (hint.position, label.clone(), hint.tooltip.is_some())
+                })
+                .collect::<Vec<_>>(),
+            vec![
+                (position(1, 12), "Fix the thing".to_string(), false),
+                (
+                    position(1, 17),
+                    "A very long title which goes…".to_string(),
+                    true
+                ),
+                (position(2, 8), "Fix the thing".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn completion_in_ci_config() {
+        let mut state = state_with_source("");
+        state.labels.insert(item("~bug", None));
+        state.milestones.insert(item("%v1", None));
+        state.issues.insert(item("#123", None));
+        let mut completions = |source: &str, character| {
+            state
+                .sources
+                .insert("/.gitlab-ci.yml".to_string(), source.to_string());
+            match completions_for_position(&state, "/.gitlab-ci.yml", &position(0, character)) {
+                Some(CompletionResponse::Array(items)) => items
+                    .into_iter()
+                    .filter_map(|item| match item.text_edit {
+                        Some(CompletionTextEdit::Edit(edit)) => Some((edit.range, edit.new_text)),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => vec![],
+            }
+        };
+
+        // without a trailing space, and within the quotes
+        assert_eq!(
+            completions("  - if: \"~b\"", 11),
+            vec![(range((0, 9), (0, 11)), "~bug".to_string())]
+        );
+        assert_eq!(
+            completions("milestone: '%'", 13),
+            vec![(range((0, 12), (0, 13)), "%v1".to_string())]
+        );
+
+        // quick actions and issues aren't offered, and `#` is a comment
+        assert!(completions("/", 1).is_empty());
+        assert!(completions("  - if: \"@\"", 10).is_empty());
+        assert!(completions("# #", 3).is_empty());
+
+        assert!(is_ci_config("/repo/.gitlab-ci.yml"));
+        assert!(!is_ci_config("/repo/ci.yml"));
+    }
+
+    #[test]
+    fn completion_of_ci_variables() {
+        let mut state = state_with_source("  - if: '$CI_COMMIT_B'\n  - echo $DEPLOY_\nSee $CI_");
+        state.sources.insert(
+            "/.gitlab-ci.yml".to_string(),
+            state.sources["/test.md"].clone(),
+        );
+        state.ci_variables = process_resource(
+            &Resource::CiVariables,
+            vec![json!({"key": "DEPLOY_TOKEN", "value": "secret", "description": "For deploys"})],
+            true,
+        );
+        let completions = |pathname, line, character| match completions_for_position(
+            &state,
+            pathname,
+            &position(line, character),
+        ) {
+            Some(CompletionResponse::Array(items)) => items,
+            _ => vec![],
+        };
+
+        let predefined = completions("/.gitlab-ci.yml", 0, 21);
+        let Some(branch) = predefined.iter().find(|i| i.label == "$CI_COMMIT_BRANCH") else {
+            panic!("expected $CI_COMMIT_BRANCH");
+        };
+        assert_eq!(branch.kind, Some(CompletionItemKind::VARIABLE));
+        assert_eq!(
+            branch.text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit {
+                range: range((0, 9), (0, 21)),
+                new_text: "$CI_COMMIT_BRANCH".to_string(),
+            }))
+        );
+
+        let Some(deploy) = completions("/.gitlab-ci.yml", 1, 17)
+            .into_iter()
+            .find(|i| i.label == "$DEPLOY_TOKEN")
+        else {
+            panic!("expected $DEPLOY_TOKEN");
+        };
+        assert_eq!(
+            deploy.documentation,
+            Some(Documentation::String("For deploys".to_string()))
+        );
+
+        // variables are only completed in CI config
+        assert!(completions("/test.md", 2, 8).is_empty());
+    }
+
+    #[test]
+    fn completion_without_trailing_space() {
+        let mut state = state_with_source("~b\n@a");
+        state.labels.insert(item("~bug", None));
+        state.members.insert(item("@alex", None));
+        state
+            .config
+            .update(&json!({"trailing_space": {"labels": false}}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        let new_text = |line| match completions_for_position(&state, "/test.md", &position(line, 2))
+        {
+            Some(CompletionResponse::Array(items)) => match &items[0].text_edit {
+                Some(CompletionTextEdit::Edit(edit)) => edit.new_text.clone(),
+                _ => panic!("expected text edit"),
+            },
+            _ => panic!("expected completions"),
+        };
+
+        assert_eq!(new_text(0), "~bug");
+        assert_eq!(new_text(1), "@alex ");
+
+        let mut config = Config::default();
+        assert!(config
+            .update(&json!({"trailing_space": {"bogus": false}}))
+            .is_err());
+    }
+
+    #[test]
+    fn completion_kind_per_resource() {
+        let mut state = state_with_source("~b\n@a\n%v");
+        state.labels.insert(item("~bug", None));
+        state.members.insert(item("@alex", None));
+        state.milestones.insert(item("%v1.0", None));
+        let kind = |state: &LspState, line| match completions_for_position(
+            state,
+            "/test.md",
+            &position(line, 2),
+        ) {
+            Some(CompletionResponse::Array(items)) => items[0].kind,
+            _ => panic!("expected completions"),
+        };
+
+        assert_eq!(kind(&state, 0), Some(CompletionItemKind::ENUM_MEMBER));
+        assert_eq!(kind(&state, 1), Some(CompletionItemKind::REFERENCE));
+        assert_eq!(kind(&state, 2), Some(CompletionItemKind::EVENT));
+
+        state
+            .config
+            .update(&json!({"completion_kind": {"members": "variable"}}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(kind(&state, 0), Some(CompletionItemKind::ENUM_MEMBER));
+        assert_eq!(kind(&state, 1), Some(CompletionItemKind::VARIABLE));
+
+        let mut config = Config::default();
+        assert!(config
+            .update(&json!({"completion_kind": {"members": "person"}}))
+            .is_err());
+        assert!(config
+            .update(&json!({"completion_kind": {"bogus": "value"}}))
+            .is_err());
+    }
+
+    #[test]
+    fn completion_of_escaped_prefixes() {
+        let mut state = LspState::default();
+        state.labels.insert(item("~bug", None));
+        state.members.insert(item("@alex", None));
+
+        for (source, character) in [
+            ("\\~", 2),
+            ("\\~b", 3),
+            ("/label \\~", 9),
+            ("~~", 2),
+            ("~~strike", 5),
+            ("@@", 2),
+        ] {
+            state
+                .sources
+                .insert("/test.md".to_string(), source.to_string());
+            assert_eq!(
+                completions_for_position(&state, "/test.md", &position(0, character)),
+                None,
+                "{source:?}"
+            );
+        }
+
+        state
+            .sources
+            .insert("/test.md".to_string(), "~b".to_string());
+        assert!(completions_for_position(&state, "/test.md", &position(0, 2)).is_some());
+    }
+
+    #[test]
+    fn reference_urls() {
+        let mut state = state_with_source("#123 !45 &6 @alex %\"13.0\" ~\"needs review\" plain");
+        state.config.project = Some("group/project".to_string());
+        let url =
+            |character| reference_url_at_position(&state, "/test.md", &position(0, character));
+
+        assert_eq!(
+            url(1).as_deref(),
+            Some("https://gitlab.com/group/project/-/issues/123")
+        );
+        assert_eq!(
+            url(6).as_deref(),
+            Some("https://gitlab.com/group/project/-/merge_requests/45")
+        );
+        assert_eq!(
+            url(10).as_deref(),
+            Some("https://gitlab.com/groups/group/-/epics/6")
+        );
+        assert_eq!(url(13).as_deref(), Some("https://gitlab.com/alex"));
+        assert_eq!(
+            url(20).as_deref(),
+            Some("https://gitlab.com/group/project/-/milestones?search_title=13.0")
+        );
+        assert_eq!(
+            url(30).as_deref(),
+            Some("https://gitlab.com/group/project/-/issues?label_name%5B%5D=needs+review")
+        );
+        assert_eq!(url(45), None);
+
+        state.config.instance_url = Some("https://example.com/gitlab/api/v4".to_string());
+        assert_eq!(
+            reference_url_at_position(&state, "/test.md", &position(0, 1)).as_deref(),
+            Some("https://example.com/gitlab/group/project/-/issues/123")
+        );
+    }
+
+    #[test]
+    fn process_members_excludes_bots() {
+        let members = vec![
+            json!({"username": "alex", "name": "Alex"}),
+            json!({"username": "project_123_bot", "name": "Deploy token"}),
+            json!({"username": "group_45_bot_0123abcd", "name": "Group token"}),
+            json!({"username": "project_manager", "name": "Sam"}),
+            json!({"username": "group_bot", "name": "Robin"}),
+        ];
+
+        assert_eq!(
+            process_resource(&Resource::Members, members.clone(), true),
+            HashSet::from([
+                item("@alex", Some("Alex")),
+                item("@project_manager", Some("Sam")),
+                item("@group_bot", Some("Robin")),
+            ])
+        );
+        assert_eq!(
+            process_resource(&Resource::Members, members, false).len(),
+            5
+        );
+    }
+
+    #[test]
+    fn completions_are_limited() {
+        let mut state = state_with_source("~ ~b");
+        state.labels = HashSet::from([
+            item("~bug", None),
+            item("~backend", None),
+            item("~frontend", None),
+            item("~docs", None),
+            item("~sub-bug", None),
+        ]);
+        let labels = |response: Option<CompletionResponse>| match response {
+            Some(CompletionResponse::List(CompletionList {
+                is_incomplete: true,
+                items,
+            })) => items
+                .into_iter()
+                .map(|item| item.label)
+                .collect::<Vec<String>>(),
+            response => panic!("expected an incomplete list, got {response:?}"),
+        };
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 1))
+        else {
+            panic!("expected all completions");
+        };
+        assert_eq!(items.len(), 5);
+
+        state.config.max_completion_items = Some(2);
+        assert_eq!(
+            labels(completions_for_position(
+                &state,
+                "/test.md",
+                &position(0, 1)
+            )),
+            vec!["~backend ", "~bug "]
+        );
+        assert_eq!(
+            labels(completions_for_position(
+                &state,
+                "/test.md",
+                &position(0, 4)
+            )),
+            vec!["~backend ", "~bug "]
+        );
+
+        state.config.max_completion_items = Some(3);
+        assert_eq!(
+            labels(completions_for_position(
+                &state,
+                "/test.md",
+                &position(0, 4)
+            )),
+            vec!["~backend ", "~bug ", "~sub-bug "]
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn workspace_root_from_initialize_params() {
+        let uri = |path: &str| Url::from_file_path(path).unwrap_or_else(|()| panic!("{path}"));
+        let folders = Some(vec![
+            WorkspaceFolder {
+                uri: uri("/folder"),
+                name: "folder".to_string(),
+            },
+            WorkspaceFolder {
+                uri: uri("/other"),
+                name: "other".to_string(),
+            },
+        ]);
+        let cwd = Some(PathBuf::from("/cwd"));
+
+        for (workspace_folders, root_uri, cwd, expected) in [
+            (
+                folders.clone(),
+                Some(uri("/root")),
+                cwd.clone(),
+                Some("/folder"),
+            ),
+            (folders.clone(), None, None, Some("/folder")),
+            (None, Some(uri("/root")), cwd.clone(), Some("/root")),
+            (Some(vec![]), Some(uri("/root")), None, Some("/root")),
+            (None, None, cwd.clone(), Some("/cwd")),
+            (None, None, None, None),
+        ] {
+            let params = InitializeParams {
+                workspace_folders,
+                root_uri,
+                ..InitializeParams::default()
+            };
+            assert_eq!(
+                workspace_root(&params, cwd),
+                expected.map(PathBuf::from),
+                "{params:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn completion_of_health_statuses() {
+        let mut state = state_with_source("/health_status o");
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 16))
+        else {
+            panic!("expected completions");
+        };
+        let labels = items.iter().map(|i| i.label.as_str()).collect::<Vec<_>>();
+        assert_eq!(labels, vec!["on_track ", "needs_attention ", "at_risk "]);
+
+        // and the quick action itself is hidden below Ultimate
+        state
+            .sources
+            .insert("/test.md".to_string(), "/".to_string());
+        state.tier = Some(Tier::Premium);
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 1))
+        else {
+            panic!("expected completions");
+        };
+        assert!(!items.iter().any(|i| i.label == "/health_status "));
+    }
+
+    #[test]
+    fn completion_of_severities() {
+        let state = state_with_source("/severity h\nh");
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 11))
+        else {
+            panic!("expected completions");
+        };
+        let labels = items.iter().map(|i| i.label.as_str()).collect::<Vec<_>>();
+        assert_eq!(labels, vec!["critical ", "high ", "medium ", "low "]);
+        assert_eq!(items[0].detail.as_deref(), Some("severity"));
+
+        assert_eq!(
+            completions_for_position(&state, "/test.md", &position(1, 1)),
+            None
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_are_limited() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let limiter = Arc::new(tokio::sync::Semaphore::new(3));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let requests = (0..20).map(|_| {
+            let limiter = limiter.clone();
+            let in_flight = in_flight.clone();
+            let most_in_flight = most_in_flight.clone();
+            tokio::spawn(async move {
+                with_permit(&limiter, async {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_in_flight.fetch_max(now, Ordering::SeqCst);
+                    tokio::task::yield_now().await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+                .await
+            })
+        });
+        futures::future::join_all(requests).await;
+
+        assert!(most_in_flight.load(Ordering::SeqCst) <= 3);
+        assert_eq!(limiter.available_permits(), 3);
+    }
+
+    #[test]
+    fn completion_of_board_lists() {
+        let boards = vec![
+            json!({
+                "name": "Development",
+                "lists": [
+                    {"label": {"name": "In Progress", "description": "Being worked on"}},
+                    {"label": {"name": "review", "description": null}},
+                ],
+            }),
+            json!({"name": "Empty", "lists": []}),
+        ];
+        let mut state = state_with_source("/board_move ~\n~");
+        state.boards = process_resource(&Resource::Boards, boards, true);
+        state.labels.insert(item("~bug", None));
+        assert_eq!(
+            state.boards,
+            HashSet::from([
+                item("~\"In Progress\"", Some("Being worked on")),
+                item("~review", None),
+            ])
+        );
 
-        let (current_word_start, current_word_end) = {
-            let boundary_chars = vec![' ', '\t'];
+        let labels = |line, character| {
+            let mut labels = completion_labels(&state, line, character);
+            labels.sort();
+            labels
+        };
+        assert_eq!(labels(0, 13), vec!["~\"In Progress\" ", "~review "]);
+        // and otherwise, labels
+        assert_eq!(labels(1, 1), vec!["~bug "]);
+    }
 
-            if let Some((line_start, line_end)) = line.split_at_checked(index) {
-                log_debug!(self, "line_start: {line_start:?}");
-                log_debug!(self, "line_end: {line_end:?}");
+    #[test]
+    fn token_expiry_warnings() {
+        let today =
+            chrono::NaiveDate::from_ymd_opt(2024, 6, 30).unwrap_or_else(|| panic!("invalid date"));
+        assert_eq!(token_expiry_warning("2024-08-30", today, 7), None);
+        assert_eq!(
+            token_expiry_warning("2024-07-05", today, 7).as_deref(),
+            Some("Your GitLab API token expires in 5 days, on 2024-07-05")
+        );
+        assert_eq!(
+            token_expiry_warning("2024-07-01", today, 7).as_deref(),
+            Some("Your GitLab API token expires tomorrow, on 2024-07-01")
+        );
+        assert_eq!(
+            token_expiry_warning("2024-06-30", today, 0).as_deref(),
+            Some("Your GitLab API token expires today")
+        );
+        assert_eq!(
+            token_expiry_warning("2024-06-01", today, 7).as_deref(),
+            Some("Your GitLab API token expired on 2024-06-01")
+        );
+        // expiry is given as a date, or a timestamp starting with one
+        assert_eq!(
+            token_expiry_warning("2024-07-01T00:00:00Z", today, 7).as_deref(),
+            Some("Your GitLab API token expires tomorrow, on 2024-07-01T00:00:00Z")
+        );
+        assert_eq!(token_expiry_warning("2024-13-01", today, 7), None);
+        assert_eq!(token_expiry_warning("soon", today, 7), None);
+    }
 
-                let start_offset = line_start
-                    .rfind(boundary_chars.as_slice())
-                    .map_or_else(|| 0, |i| i + 1);
-                let end_offset = line_end
-                    .find(boundary_chars.as_slice())
-                    .unwrap_or(line_end.len());
+    #[test]
+    fn tier_from_instance_metadata() {
+        let ce = json!({"version": "17.0.0", "enterprise": false});
+        let ee = json!({"version": "17.0.0-ee", "enterprise": true});
 
-                log_debug!(self, "offset: {start_offset}..{end_offset}");
+        assert_eq!(tier_from_metadata(&ce, None), Some(Tier::Free));
+        assert_eq!(
+            tier_from_metadata(&ee, Some(&json!({"plan": "premium"}))),
+            Some(Tier::Premium)
+        );
+        assert_eq!(
+            tier_from_metadata(&ee, Some(&json!({"plan": "gold"}))),
+            Some(Tier::Ultimate)
+        );
+        assert_eq!(
+            tier_from_metadata(&ee, Some(&Value::Null)),
+            Some(Tier::Free)
+        );
+        // eg the license is only readable by admins
+        assert_eq!(
+            tier_from_metadata(&ee, Some(&json!({"message": "403 Forbidden"}))),
+            None
+        );
+        assert_eq!(tier_from_metadata(&ee, None), None);
+        // eg an old instance, without `enterprise`
+        assert_eq!(
+            tier_from_metadata(&json!({"version": "15.0.0"}), None),
+            None
+        );
+    }
 
-                (start_offset, index + end_offset)
-            } else {
-                (index, index)
-            }
-        };
-        let ch = line
-            .chars()
-            .nth(current_word_start)
-            .expect("char (column) should exist");
-
-        log_debug!(self, "line: {line}");
-        log_debug!(self, "ch: {ch}");
-
-        let (completions, completion_kind) = match ch {
-            '/' => (
-                // https://docs.gitlab.com/ee/user/project/quick_actions.html
-                // these are all aimed at creating *new* issues at this time, so
-                // eg /reopen or /unassign aren't relevant
-                vec![
-                    ("/assign ", "Assign users"),
-                    ("/blocked_by ", "Is blocked by other issues"),
-                    ("/blocks ", "Blocks other issues"),
-                    ("/due ", "Due on a certain date"),
-                    ("/relate ", "Relates to other issues"),
-                    ("/label ", "Add labels"),
-                    ("/milestone ", "Add to milestone"),
-                    ("/title ", "Set title"),
-                ]
-                .iter()
-                .map(|i| CompletionItemData {
-                    completion: i.0.to_string(),
-                    description: Some(i.1.to_string()),
+    #[test]
+    fn quick_actions_for_tier() {
+        let mut state = state_with_source("/");
+
+        assert!(completion_labels(&state, 0, 1).contains(&"/blocks ".to_string()));
+
+        state.tier = Some(Tier::Free);
+        let free = completion_labels(&state, 0, 1);
+        assert!(!free.contains(&"/blocks ".to_string()));
+        assert!(free.contains(&"/relate ".to_string()));
+
+        state.tier = Some(Tier::Premium);
+        assert!(completion_labels(&state, 0, 1).contains(&"/blocks ".to_string()));
+    }
+
+    #[test]
+    fn completion_of_emoji_after_award_and_react() {
+        let mut state = state_with_source("/award :thu\n/react :\n/re");
+        state.config.document_context = Some(DocumentContext::Comment);
+
+        let emoji = completion_labels(&state, 0, 11);
+        assert!(emoji.contains(&":thumbsup: ".to_string()));
+        assert_eq!(emoji, completion_labels(&state, 1, 8));
+
+        let quick_actions = completion_labels(&state, 2, 3);
+        assert!(quick_actions.contains(&"/react ".to_string()));
+
+        // `/react` is a newer alias of `/award`
+        state.version = version_from_metadata(&json!({"version": "16.6.0"}));
+        let quick_actions = completion_labels(&state, 2, 3);
+        assert!(!quick_actions.contains(&"/react ".to_string()));
+        assert!(completion_labels(&state, 0, 1).contains(&"/award ".to_string()));
+    }
+
+    #[test]
+    fn completion_of_service_desk_emails() {
+        let mut state = state_with_source("/add_\n/add_email al\n/add_email [");
+        state.config.document_context = Some(DocumentContext::IssueComment);
+        state
+            .config
+            .update(&json!({"resources": ["contacts"]}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        state
+            .contacts
+            .insert(item("[contact:alex@example.com]", Some("Alex")));
+
+        // without Service Desk, the quick action isn't offered
+        assert!(!completion_labels(&state, 0, 5).contains(&"/add_email ".to_string()));
+
+        state.service_desk = true;
+        assert!(completion_labels(&state, 0, 5).contains(&"/add_email ".to_string()));
+        assert_eq!(completion_labels(&state, 1, 13), vec!["alex@example.com "]);
+        // which takes emails, rather than contacts' references
+        assert!(completion_labels(&state, 2, 12).is_empty());
+    }
+
+    #[test]
+    fn completion_of_weight_snippet() {
+        let mut state = state_with_source("/wei");
+        state.client_support.snippets = true;
+        let new_text = |state: &LspState| {
+            let Some(CompletionResponse::Array(items)) =
+                completions_for_position(state, "/test.md", &position(0, 4))
+            else {
+                return None;
+            };
+            items
+                .into_iter()
+                .find(|i| i.label == "/weight ")
+                .and_then(|weight| match weight.text_edit {
+                    Some(CompletionTextEdit::Edit(edit)) => Some(edit.new_text),
+                    _ => None,
                 })
-                .collect::<Vec<CompletionItemData>>(),
-                Resource::QuickActions,
-            ),
-            '@' => (state.members.iter().cloned().collect(), Resource::Members),
-            '%' => (
-                state.milestones.iter().cloned().collect(),
-                Resource::Milestones,
-            ),
-            '~' => (state.labels.iter().cloned().collect(), Resource::Labels),
-            _ => return Ok(None),
         };
 
-        let detail = match completion_kind {
-            Resource::Labels => "label",
-            Resource::Members => "username",
-            Resource::Milestones => "milestone",
-            Resource::QuickActions => "quick action",
+        assert_eq!(new_text(&state).as_deref(), Some("/weight ${1:1}"));
+
+        // weights require Premium
+        state.tier = Some(Tier::Free);
+        assert_eq!(new_text(&state), None);
+        state.tier = Some(Tier::Premium);
+        assert_eq!(new_text(&state).as_deref(), Some("/weight ${1:1}"));
+
+        // and only apply to issues
+        state.config.document_context = Some(DocumentContext::MergeRequest);
+        assert_eq!(new_text(&state), None);
+    }
+
+    #[test]
+    fn quick_actions_for_version() {
+        let mut state = state_with_source("/");
+
+        assert!(completion_labels(&state, 0, 1).contains(&"/add_child ".to_string()));
+
+        // too old for work items
+        state.version = version_from_metadata(&json!({"version": "16.4.2-ee"}));
+        let old = completion_labels(&state, 0, 1);
+        assert!(!old.contains(&"/add_child ".to_string()));
+        assert!(!old.contains(&"/set_parent ".to_string()));
+        assert!(!old.contains(&"/remove_parent ".to_string()));
+        assert!(old.contains(&"/relate ".to_string()));
+
+        state.version = version_from_metadata(&json!({"version": "16.5.0"}));
+        assert!(completion_labels(&state, 0, 1).contains(&"/add_child ".to_string()));
+    }
+
+    #[test]
+    fn version_from_instance_metadata() {
+        let version = |version: &str| version_from_metadata(&json!({ "version": version }));
+        assert_eq!(version("17.0.0"), Some((17, 0)));
+        assert_eq!(version("16.11.3-ee"), Some((16, 11)));
+        assert_eq!(version("17.2.0-pre"), Some((17, 2)));
+        assert_eq!(version("unknown"), None);
+        assert_eq!(version_from_metadata(&json!({})), None);
+    }
+
+    #[test]
+    fn config_with_missing_ca_cert() {
+        let mut config = Config::default();
+        let Err(err) = config.update(&json!({"ca_cert_path": "/no/such/ca.pem"})) else {
+            panic!("expected an error");
         };
-        let completion_kind = match completion_kind {
-            Resource::Labels | Resource::Members | Resource::Milestones => {
-                Some(CompletionItemKind::CONSTANT)
-            }
-            Resource::QuickActions => Some(CompletionItemKind::KEYWORD),
+        assert_eq!(
+            err.data,
+            Some(json!({"kind": "invalid_ca_cert", "path": "/no/such/ca.pem"}))
+        );
+        assert_eq!(config.ca_cert_path, None);
+    }
+
+    #[test]
+    fn lazy_resources_are_loaded_once() {
+        let mut state = state_with_source("@al ~\n/clone pro");
+        state
+            .config
+            .update(&json!({"load": {"members": "lazy", "labels": "lazy", "issues": "eager"}}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert!(state.config.is_lazy(Resource::Members));
+        assert!(!state.config.is_lazy(Resource::Issues));
+        assert!(state
+            .config
+            .update(&json!({"load": {"members": "later"}}))
+            .is_err());
+
+        assert_eq!(
+            resources_for_position(&state, "/test.md", &position(0, 3)),
+            vec![Resource::Members, Resource::Groups]
+        );
+        assert_eq!(
+            resources_for_position(&state, "/test.md", &position(1, 10)),
+            vec![Resource::Projects]
+        );
+        assert_eq!(
+            resources_for_position(&state, "/test.md", &position(1, 3)),
+            vec![]
+        );
+
+        // only what would be completed there, by the same rules
+        for (source, character) in [("(@al", 4), ("~~strike", 3), ("fix #1", 6), ("branch", 3)] {
+            state
+                .sources
+                .insert("/test.md".to_string(), source.to_string());
+            assert_eq!(
+                resources_for_position(&state, "/test.md", &position(0, character)),
+                vec![],
+                "{source}"
+            );
+        }
+
+        // groups aren't enabled, and the members are only fetched once, even
+        // by concurrent completions
+        let members = [Resource::Members, Resource::Groups];
+        assert_eq!(state.start_loading(&members), vec![Resource::Members]);
+        assert_eq!(state.start_loading(&members), vec![]);
+        state.loading.clear();
+        state.set_resource(Resource::Members, HashSet::from([item("@alex", None)]));
+        assert_eq!(state.start_loading(&members), vec![]);
+
+        // and eager resources are never fetched lazily
+        assert_eq!(state.start_loading(&[Resource::Issues]), vec![]);
+    }
+
+    #[test]
+    fn labels_are_case_sensitive() {
+        let mut state = state_with_source("/label ~Bug ~\n~bug");
+        state.labels = HashSet::from([item("~Bug", Some("Upper")), item("~bug", Some("lower"))]);
+
+        // both exist, and only the applied one is excluded
+        assert_eq!(completion_labels(&state, 0, 13), vec!["~bug "]);
+
+        state.config.label_exclude = vec!["B".to_string()];
+        state
+            .sources
+            .insert("/test.md".to_string(), "~".to_string());
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 1))
+        else {
+            panic!("expected completions");
         };
-        let range = Range {
-            start: Position {
-                line: params.text_document_position.position.line,
-                character: current_word_start as u32,
-            },
-            end: Position {
-                line: params.text_document_position.position.line,
-                character: current_word_end as u32,
+        assert_eq!(
+            items.into_iter().map(|i| i.label).collect::<Vec<_>>(),
+            vec!["~bug "]
+        );
+
+        // whereas limiting completions matches what's typed like editors do
+        state.config.label_exclude = vec![];
+        state.config.max_completion_items = Some(1);
+        state
+            .sources
+            .insert("/test.md".to_string(), "~b".to_string());
+        let Some(CompletionResponse::List(list)) =
+            completions_for_position(&state, "/test.md", &position(0, 2))
+        else {
+            panic!("expected an incomplete list");
+        };
+        assert_eq!(list.items.len(), 1);
+    }
+
+    #[test]
+    fn completion_documentation_links_to_gitlab() {
+        let mut state = state_with_source("~\n%");
+        state.config.project = Some("group/project".to_string());
+        state.client_support.markdown_documentation = true;
+        state.labels = HashSet::from([item("~\"needs review\"", Some("Ready for review"))]);
+        state.milestones = process_resource(
+            &Resource::Milestones,
+            vec![json!({
+                "title": "v1.0",
+                "description": null,
+                "expired": false,
+                "web_url": "https://gitlab.com/group/project/-/milestones/3",
+            })],
+            true,
+        );
+        let documentation = |line, character| match completions_for_position(
+            &state,
+            "/test.md",
+            &position(line, character),
+        ) {
+            Some(CompletionResponse::Array(items)) => match &items[0].documentation {
+                Some(Documentation::MarkupContent(content)) => content.value.clone(),
+                documentation => panic!("unexpected documentation: {documentation:?}"),
             },
+            response => panic!("unexpected response: {response:?}"),
         };
 
-        let completions: Vec<CompletionItem> = completions
-            .iter()
-            .map(|comp| {
-                let mut completion =
-                    CompletionItem::new_simple(comp.completion.to_string(), detail.to_string());
-
-                completion.kind = completion_kind.clone();
-                completion.documentation =
-                    comp.description.clone().map(|d| Documentation::String(d));
-                completion.text_edit = Some(CompletionTextEdit::Edit(TextEdit {
-                    range,
-                    new_text: comp.completion.to_string(),
-                }));
+        assert_eq!(
+            documentation(0, 1),
+            "Ready for review\n\n[Open in GitLab](https://gitlab.com/group/project/-/issues?label_name%5B%5D=needs+review)"
+        );
+        assert_eq!(
+            documentation(1, 1),
+            "[Open in GitLab](https://gitlab.com/group/project/-/milestones/3)"
+        );
+    }
+
+    #[test]
+    fn resource_to_refresh_from_arguments() {
+        assert_eq!(
+            resource_to_refresh(&[json!("labels")]),
+            Some(Resource::Labels)
+        );
+        assert_eq!(
+            resource_to_refresh(&[json!("members"), json!("extra")]),
+            Some(Resource::Members)
+        );
+        // these aren't fetched
+        assert_eq!(resource_to_refresh(&[json!("quick_actions")]), None);
+        assert_eq!(resource_to_refresh(&[json!("severities")]), None);
+        assert_eq!(resource_to_refresh(&[json!("everything")]), None);
+        assert_eq!(resource_to_refresh(&[json!(1)]), None);
+        assert_eq!(resource_to_refresh(&[]), None);
+    }
+
+    #[test]
+    fn members_sort_by_access_level() {
+        let mut state = state_with_source("@");
+        let members = vec![
+            json!({"username": "alex", "name": "Alex", "access_level": 30}),
+            json!({"username": "sam", "name": "Sam", "access_level": 40}),
+            json!({"username": "robin", "name": "Robin", "access_level": 30}),
+        ];
+        state.member_data = process_member_data(&members);
+        state.members = process_resource(&Resource::Members, members, true);
+        state.groups.insert(item("@group", None));
+        let sorted = |state: &LspState| {
+            let Some(CompletionResponse::Array(mut items)) =
+                completions_for_position(state, "/test.md", &position(0, 1))
+            else {
+                panic!("expected completions");
+            };
+            items.sort_by(|a, b| {
+                let key = |item: &CompletionItem| {
+                    item.sort_text.clone().unwrap_or_else(|| item.label.clone())
+                };
+                key(a).cmp(&key(b))
+            });
+            items.into_iter().map(|i| i.label).collect::<Vec<_>>()
+        };
+
+        // the maintainer sorts above the developers
+        assert_eq!(
+            sorted(&state),
+            vec!["@sam ", "@alex ", "@robin ", "@group "]
+        );
+
+        state
+            .config
+            .update(&json!({"members_sort": "alphabetical"}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(
+            sorted(&state),
+            vec!["@alex ", "@group ", "@robin ", "@sam "]
+        );
+    }
 
-                // To use a snippet
-                // completion.insert_text = Some(period.snippet.clone());
-                // completion.insert_text_format = Some(InsertTextFormat::SNIPPET);
+    #[test]
+    fn document_keys_for_uri_schemes() {
+        let uri = |uri: &str| Url::parse(uri).unwrap_or_else(|err| panic!("{err}"));
 
-                completion
+        for (uri, key) in [
+            (uri("file:///notes/todo.md"), "/notes/todo.md"),
+            (uri("untitled:Untitled-1"), "untitled:Untitled-1"),
+            (uri("inmemory://model/1"), "inmemory://model/1"),
+        ] {
+            assert_eq!(document_key(&uri), key);
+            assert_eq!(document_uri(key), Some(uri));
+        }
+    }
+
+    #[tokio::test]
+    async fn completion_in_untitled_document() {
+        let mut state = LspState::default();
+        state.labels.insert(item("~bug", None));
+        let service = test_service(state);
+        let lsp = service.inner();
+        let uri = Url::parse("untitled:Untitled-1").unwrap_or_else(|err| panic!("{err}"));
+
+        lsp.did_open(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: uri.clone(),
+                language_id: "markdown".to_string(),
+                version: 1,
+                text: "~".to_string(),
+            },
+        })
+        .await;
+        assert!(lsp
+            .state
+            .lock()
+            .await
+            .sources
+            .contains_key("untitled:Untitled-1"));
+
+        let response = lsp
+            .completion(CompletionParams {
+                text_document_position: TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier { uri },
+                    position: position(0, 1),
+                },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
             })
-            .collect();
+            .await;
+        let labels = match response {
+            Ok(Some(CompletionResponse::Array(items))) => items,
+            Ok(Some(CompletionResponse::List(list))) => list.items,
+            other => panic!("expected completions, got {other:?}"),
+        };
+        assert_eq!(
+            labels.iter().map(|l| l.label.as_str()).collect::<Vec<_>>(),
+            vec!["~bug "]
+        );
+    }
+
+    #[test]
+    fn resource_counts_of_loaded_resources() {
+        let mut state = LspState::default();
+        state.config.lazy.insert(Resource::Issues);
+        state.set_resource(Resource::Labels, HashSet::from([item("~bug", None)]));
+        state.set_resource(Resource::Members, HashSet::new());
 
-        Ok(Some(CompletionResponse::Array(completions)))
+        let counts = resource_counts(&state);
+        assert_eq!(counts["labels"], json!(1));
+        assert_eq!(counts["members"], json!(0));
+        // lazy, and yet to be fetched
+        assert_eq!(counts["issues"], json!(null));
+        // not enabled
+        assert!(counts.get("boards").is_none());
     }
-}
 
-fn gitlab_resource_url(api_base: &str, project: &str, resource_kind: &Resource) -> String {
-    let api_base = api_base.strip_suffix("/").unwrap_or(api_base);
-    let project = project.replace('/', "%2F");
-    let resource = match resource_kind {
-        Resource::Labels => "labels",
-        Resource::Members => "members/all",
-        Resource::Milestones => "milestones",
-        Resource::QuickActions => unreachable!(),
-    };
-    // See: https://docs.gitlab.com/ee/api/rest/index.html#offset-based-pagination
-    format!("{api_base}/projects/{project}/{resource}?per_page=100")
-}
+    #[test]
+    fn current_user_completion_from_user() {
+        let Some(me) = current_user_completion(&json!({
+            "username": "alex",
+            "name": "Alex",
+            "web_url": "https://gitlab.com/alex",
+        })) else {
+            panic!("expected completion");
+        };
+        assert_eq!(me.completion, "me");
+        assert_eq!(me.description.as_deref(), Some("Yourself, Alex (@alex)"));
+        assert_eq!(me.url.as_deref(), Some("https://gitlab.com/alex"));
 
-fn make_request(
-    client: &reqwest::Client,
-    api_base: &str,
-    api_key: &str,
-    project: &str,
-    resource_kind: Resource,
-) -> tokio::task::JoinHandle<(Resource, Value)> {
-    let label_url = gitlab_resource_url(api_base, &project, &resource_kind);
+        // eg the token is invalid
+        assert_eq!(
+            current_user_completion(&json!({"message": "401 Unauthorized"})),
+            None
+        );
+    }
+
+    #[test]
+    fn assign_completes_me_first() {
+        let labels = |source: &str| {
+            let mut state = state_with_source(source);
+            state.members.insert(item("@alex", None));
+            state
+                .member_data
+                .insert("alex".to_string(), MemberData { access_level: 50 });
+            state.current_user = current_user_completion(&json!({"username": "sam"}));
+            let character = source.len() as u32;
+            match completions_for_position(&state, "/test.md", &position(0, character)) {
+                Some(CompletionResponse::Array(mut items)) => {
+                    items.sort_by(|a, b| a.sort_text.cmp(&b.sort_text));
+                    items.into_iter().map(|i| i.label).collect::<Vec<_>>()
+                }
+                _ => vec![],
+            }
+        };
 
-    let cl = client
-        .get(label_url)
-        .bearer_auth(&api_key)
-        .try_clone()
-        .expect("Cloning client");
+        assert_eq!(labels("/assign @"), vec!["me ", "@alex "]);
+        assert_eq!(labels("/assign @alex m"), vec!["me "]);
+        // only quick actions which accept `me`
+        assert_eq!(labels("/unassign @"), vec!["@alex "]);
+        assert!(labels("/unassign m").is_empty());
+        assert_eq!(labels("Thanks @"), vec!["@alex "]);
+    }
 
-    tokio::spawn(async move {
-        let res = cl.send().await.expect("awaiting request");
-        // let pages = res
-        //     .headers()
-        //     .get("x-total-pages")
-        //     .map_or(1, |v| v.to_str().map_or(1, |s| s.parse().unwrap_or(1)));
-        let json: serde_json::Value = res.json().await.expect("decoding JSON");
-        (resource_kind, json)
-    })
-}
+    #[test]
+    fn due_dates_from_today() {
+        let due_dates = |today: &str| {
+            let Ok(today) = chrono::NaiveDate::parse_from_str(today, "%Y-%m-%d") else {
+                panic!("invalid date: {today}");
+            };
+            due_date_completions(today)
+                .into_iter()
+                .map(|date| (date.completion, date.description.unwrap_or_default()))
+                .collect::<Vec<_>>()
+        };
+        let date = |date: &str, description: &str| (date.to_string(), description.to_string());
 
-fn process_resource(
-    resource_kind: &Resource,
-    resources: Vec<Value>,
-) -> HashSet<CompletionItemData> {
-    resources
-        .into_iter()
-        .filter_map(|r| match r {
-            Value::Object(resource) => {
-                // https://docs.gitlab.com/ee/api/labels.html#list-labels
-                // https://docs.gitlab.com/ee/api/milestones.html
-                // https://docs.gitlab.com/ee/api/members.html#list-all-members-of-a-group-or-project
+        assert_eq!(
+            due_dates("2024-02-10"),
+            vec![
+                date("2024-02-10", "Today, Saturday"),
+                date("2024-02-11", "Tomorrow, Sunday"),
+                date("2024-02-12", "Start of next week, Monday"),
+                date("2024-02-29", "End of this month, Thursday"),
+                date("2024-03-31", "End of this quarter (Q1), Sunday"),
+            ]
+        );
+        // the end of the month and quarter are the end of the week
+        assert_eq!(
+            due_dates("2024-06-28"),
+            vec![
+                date("2024-06-28", "Today, Friday"),
+                date("2024-06-29", "Tomorrow, Saturday"),
+                date("2024-06-30", "End of this week, Sunday"),
+                date("2024-07-01", "Start of next week, Monday"),
+            ]
+        );
+        assert_eq!(
+            due_dates("2024-11-15")[5],
+            date("2024-12-31", "End of this quarter (Q4), Tuesday")
+        );
+        // across the end of the year
+        assert_eq!(
+            due_dates("2024-12-31")[2],
+            date("2025-01-05", "End of this week, Sunday")
+        );
+    }
 
-                let (gitlab_prefix, value_key, description_key) = match resource_kind {
-                    Resource::Labels => ("~", "name", "description"),
-                    Resource::Members => ("@", "username", "name"),
-                    Resource::Milestones => {
-                        if let Value::Bool(true) = resource["expired"] {
-                            return None;
-                        }
+    #[test]
+    fn today_in_utc_offset() {
+        let mut config = Config::default();
+        config
+            .update(&json!({"utc_offset": "-05:00"}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(
+            config.utc_offset.map(|offset| offset.local_minus_utc()),
+            Some(-5 * 3600)
+        );
+        assert!(config
+            .update(&json!({"utc_offset": "Europe/Paris"}))
+            .is_err());
 
-                        ("%", "title", "description")
-                    }
-                    Resource::QuickActions => unreachable!(),
-                };
+        // the furthest apart time zones are always on different days
+        let offset = |offset: &str| offset.parse::<chrono::FixedOffset>().ok();
+        let days_apart = (today(offset("+14:00")) - today(offset("-12:00"))).num_days();
+        assert!((1..=2).contains(&days_apart));
+    }
 
-                let (completion, description) =
-                    match (&resource[value_key], &resource[description_key]) {
-                        (Value::String(ref completion), Value::String(ref description))
-                            if !description.is_empty() =>
-                        {
-                            (completion, Some(description.clone()))
-                        }
-                        (Value::String(ref completion), _) => (completion, None),
-                        (_, _) => {
-                            return None;
-                        }
-                    };
+    #[test]
+    fn diagnostics_for_unknown_quick_actions() {
+        let source = [
+            "/asign @alex",
+            "  /foobar",
+            "/assign @alex",
+            "See /usr/bin or",
+            "/usr/bin/env",
+            "```",
+            "/not_run",
+            "```",
+        ]
+        .join("\n");
 
-                let completion = if completion.contains(&[' ']) {
-                    format!(r#"{gitlab_prefix}"{completion}" "#)
-                } else {
-                    format!("{gitlab_prefix}{completion} ")
-                };
+        let diagnostics = quick_action_diagnostics(&source)
+            .into_iter()
+            .map(|d| (d.message, d.range))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            diagnostics,
+            vec![
+                (
+                    "Unknown quick action '/asign'".to_string(),
+                    range((0, 0), (0, 6))
+                ),
+                (
+                    "Unknown quick action '/foobar'".to_string(),
+                    range((1, 2), (1, 9))
+                ),
+            ]
+        );
+    }
 
-                Some(CompletionItemData {
-                    completion,
-                    description,
-                })
+    #[test]
+    fn label_data_from_labels_api() {
+        let labels = [
+            json!({"id": 7, "name": "bug", "color": "#d9534f", "text_color": "#FFFFFF"}),
+            json!({"id": 8, "name": "needs review", "color": null}),
+            json!({"name": "no id"}),
+        ];
+        assert_eq!(
+            process_label_data(&labels),
+            HashMap::from([
+                (
+                    "bug".to_string(),
+                    LabelData {
+                        id: 7,
+                        color: Some("#d9534f".to_string()),
+                    }
+                ),
+                ("needs review".to_string(), LabelData { id: 8, color: None }),
+            ])
+        );
+    }
+
+    #[test]
+    fn recent_uses_of_labels_and_milestones() {
+        let issues = [
+            json!({"iid": 1, "labels": ["bug", "priority::high"], "milestone": {"title": "v1"}}),
+            json!({"iid": 2, "labels": ["bug"], "milestone": null}),
+            json!({"iid": 3, "labels": [], "milestone": {"title": "v1"}}),
+        ];
+        assert_eq!(
+            recent_uses(issues.iter()),
+            HashMap::from([
+                ((Resource::Labels, "bug".to_string()), 2),
+                ((Resource::Labels, "priority::high".to_string()), 1),
+                ((Resource::Milestones, "v1".to_string()), 2),
+            ])
+        );
+    }
+
+    #[test]
+    fn completion_ranked_by_recency() {
+        let mut state = state_with_source("~\n%");
+        state.labels = HashSet::from([
+            item("~bug", None),
+            item("~docs", None),
+            item("~urgent", None),
+        ]);
+        state.milestones = HashSet::from([item("%v1", None), item("%v2", None)]);
+        state.recent_uses = HashMap::from([
+            ((Resource::Labels, "bug".to_string()), 3),
+            ((Resource::Labels, "urgent".to_string()), 7),
+            ((Resource::Milestones, "v2".to_string()), 2),
+        ]);
+        let labels = |state: &LspState, line| match completions_for_position(
+            state,
+            "/test.md",
+            &position(line, 1),
+        ) {
+            Some(CompletionResponse::Array(mut items)) => {
+                items.sort_by(|a, b| a.sort_text.cmp(&b.sort_text).then(a.label.cmp(&b.label)));
+                items.into_iter().map(|i| i.label).collect::<Vec<_>>()
             }
-            Value::Null
-            | Value::Bool(_)
-            | Value::Number(_)
-            | Value::String(_)
-            | Value::Array(_) => None,
-        })
-        .collect()
+            _ => vec![],
+        };
+
+        assert_eq!(labels(&state, 0), vec!["~bug ", "~docs ", "~urgent "]);
+        assert_eq!(labels(&state, 1), vec!["%v1 ", "%v2 "]);
+
+        state.config.rank_by_recency = true;
+        assert_eq!(labels(&state, 0), vec!["~urgent ", "~bug ", "~docs "]);
+        assert_eq!(labels(&state, 1), vec!["%v2 ", "%v1 "]);
+
+        let mut config = Config::default();
+        config
+            .update(&json!({"rank_by_recency": true}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert!(!config.fetches_same_resources(&Config::default()));
+    }
+
+    #[test]
+    fn labels_used_by_issues() {
+        let issues = [
+            json!({"iid": 1, "labels": ["bug", "priority::high"]}),
+            json!({"iid": 2, "labels": []}),
+            json!({"iid": 3, "labels": ["bug", "needs review"]}),
+        ];
+        assert_eq!(
+            used_label_names(issues.iter()),
+            HashSet::from([
+                "bug".to_string(),
+                "priority::high".to_string(),
+                "needs review".to_string(),
+            ])
+        );
+
+        let mut config = Config::default();
+        config
+            .update(&json!({"labels_from": "used"}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert_eq!(config.labels_from, LabelsFrom::Used);
+        assert!(!config.fetches_same_resources(&Config::default()));
+        assert!(config.update(&json!({"labels_from": "some"})).is_err());
+    }
+
+    #[tokio::test]
+    async fn reopening_document_replaces_it() {
+        let service = test_service(LspState::default());
+        let lsp = service.inner();
+        let open = |text: &str, version| DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: Url::parse("file:///test.md").unwrap_or_else(|err| panic!("{err}")),
+                language_id: "markdown".to_string(),
+                version,
+                text: text.to_string(),
+            },
+        };
+
+        // eg after the client reconnects
+        lsp.did_open(open("/asign @alex", 1)).await;
+        lsp.did_open(open("/assign @alex", 1)).await;
+
+        let state = lsp.state.lock().await;
+        assert_eq!(
+            state.sources,
+            HashMap::from([("/test.md".to_string(), "/assign @alex".to_string())])
+        );
+    }
+
+    #[test]
+    fn quick_action_usage_from_snippet() {
+        let usage = |name: &str| match QUICK_ACTIONS.iter().find(|qa| qa.name == name) {
+            Some(qa) => qa.usage(),
+            None => panic!("no such quick action: /{name}"),
+        };
+
+        assert_eq!(usage("assign"), "/assign @user");
+        assert_eq!(usage("close"), "/close");
+        // with several placeholders
+        assert_eq!(usage("timeline"), "/timeline comment | YYYY-MM-DD HH:MM");
+    }
+
+    #[test]
+    fn completion_of_timeline_snippet() {
+        let mut state = state_with_source("/timel");
+        state.client_support.snippets = true;
+        state
+            .config
+            .update(&json!({"document_context": "issue_comment"}))
+            .unwrap_or_else(|err| panic!("{err}"));
+
+        let Some(CompletionResponse::Array(items)) =
+            completions_for_position(&state, "/test.md", &position(0, 6))
+        else {
+            panic!("expected completions");
+        };
+        let Some(timeline) = items.into_iter().find(|i| i.label == "/timeline ") else {
+            panic!("expected /timeline");
+        };
+        assert_eq!(
+            timeline.text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit {
+                range: range((0, 0), (0, 6)),
+                new_text: "/timeline ${1:comment} | ${2:YYYY-MM-DD} ${3:HH:MM}".to_string(),
+            }))
+        );
+    }
+
+    #[test]
+    fn completion_waits_for_min_chars() {
+        let mut state = state_with_source("@\n@a\n@al\n/as");
+        state.members.insert(item("@alex", None));
+        state
+            .config
+            .update(&json!({"min_chars_before_complete": 2}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        let labels = |line, character| match completions_for_position(
+            &state,
+            "/test.md",
+            &position(line, character),
+        ) {
+            Some(CompletionResponse::Array(items)) => (
+                false,
+                items.into_iter().map(|i| i.label).collect::<Vec<_>>(),
+            ),
+            Some(CompletionResponse::List(list)) => (
+                list.is_incomplete,
+                list.items.into_iter().map(|i| i.label).collect::<Vec<_>>(),
+            ),
+            None => panic!("expected completions"),
+        };
+
+        assert_eq!(labels(0, 1), (true, vec![]));
+        assert_eq!(labels(1, 2), (true, vec![]));
+        assert_eq!(labels(2, 3), (false, vec!["@alex ".to_string()]));
+        // quick actions aren't fetched, so are always offered
+        assert!(labels(3, 3).1.contains(&"/assign ".to_string()));
+
+        let mut config = Config::default();
+        assert!(config
+            .update(&json!({"min_chars_before_complete": -1}))
+            .is_err());
+    }
+
+    #[test]
+    fn deprecation_warning_from_headers() {
+        assert_eq!(deprecation_warning(Resource::Labels, None, None), None);
+        assert_eq!(
+            deprecation_warning(Resource::Labels, None, Some("Sat, 1 Nov 2025 00:00:00 GMT")),
+            None
+        );
+        assert_eq!(
+            deprecation_warning(Resource::Labels, Some("false"), None),
+            None
+        );
+        assert_eq!(
+            deprecation_warning(Resource::Labels, Some("@1688169599"), None).as_deref(),
+            Some(
+                "The GitLab API used to fetch labels is deprecated, and may be removed, so \
+                they may no longer be completed; please check for an update of the language \
+                server"
+            )
+        );
+        assert_eq!(
+            deprecation_warning(
+                Resource::Members,
+                Some("true"),
+                Some("Sat, 1 Nov 2025 00:00:00 GMT")
+            )
+            .as_deref(),
+            Some(
+                "The GitLab API used to fetch members is deprecated, and will be removed \
+                after Sat, 1 Nov 2025 00:00:00 GMT, so they may no longer be completed; \
+                please check for an update of the language server"
+            )
+        );
+    }
+
+    #[test]
+    fn completion_cache_of_large_resources() {
+        let mut state = state_with_source("~bu");
+        state
+            .config
+            .update(&json!({"max_completion_items": 2}))
+            .unwrap_or_else(|err| panic!("{err}"));
+        for label in ["~bug", "~build", "~bugfix", "~docs"] {
+            state.labels.insert(item(label, None));
+        }
+        let labels =
+            |state: &LspState| match completions_for_position(state, "/test.md", &position(0, 3)) {
+                Some(CompletionResponse::List(list)) => {
+                    list.items.into_iter().map(|i| i.label).collect::<Vec<_>>()
+                }
+                _ => panic!("expected an incomplete list"),
+            };
+
+        assert_eq!(labels(&state), vec!["~bug ", "~bugfix "]);
+        let Ok(cache) = state.completion_cache.lock() else {
+            panic!("expected cache");
+        };
+        assert_eq!(cache.entries.len(), 1);
+        drop(cache);
+
+        // the cached completions are reused until the resource changes
+        state.labels.insert(item("~bu", None));
+        assert_eq!(labels(&state), vec!["~bug ", "~bugfix "]);
+        let labels_with = state.labels.clone();
+        state.set_resource(Resource::Labels, labels_with);
+        assert_eq!(labels(&state), vec!["~bu ", "~bug "]);
+    }
+
+    #[test]
+    fn completion_cache_evicts_least_recently_used() {
+        let mut cache = CompletionCache::default();
+        let key = |n: usize| (Resource::Labels, "~".to_string(), n);
+        for n in 0..COMPLETION_CACHE_SIZE {
+            cache.insert(key(n), vec![]);
+        }
+        assert!(cache.get(&key(0)).is_some());
+
+        cache.insert(key(COMPLETION_CACHE_SIZE), vec![]);
+        assert_eq!(cache.entries.len(), COMPLETION_CACHE_SIZE);
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(1)).is_none());
+    }
+
+    #[test]
+    fn target_branch_completes_default_branch_first() {
+        let mut state = state_with_source("/target_branch m");
+        let branches = vec![
+            json!({"name": "feature", "default": false, "commit": {"title": "WIP"}}),
+            json!({"name": "main", "default": true, "commit": {"title": "Release"}}),
+            json!({"name": "maintenance", "default": false, "commit": {"title": "Backport"}}),
+        ];
+        state.default_branch = process_default_branch(&branches);
+        state.branches = process_resource(&Resource::Branches, branches, true);
+        let Some(CompletionResponse::Array(mut items)) =
+            completions_for_position(&state, "/test.md", &position(0, 16))
+        else {
+            panic!("expected completions");
+        };
+        items.sort_by(|a, b| {
+            let key = |item: &CompletionItem| {
+                item.sort_text.clone().unwrap_or_else(|| item.label.clone())
+            };
+            key(a).cmp(&key(b))
+        });
+
+        assert_eq!(
+            items
+                .iter()
+                .map(|i| (i.label.as_str(), i.detail.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("main ", Some("default branch")),
+                ("feature ", Some("branch")),
+                ("maintenance ", Some("branch")),
+            ]
+        );
+    }
 }
@@ -1,3 +1,5 @@
+mod config_file;
+mod git;
 mod lsp;
 
 #[tokio::main]
@@ -37,9 +39,12 @@ async fn main() {
 
         //     return;
         // }
-        Some(arg) if arg == "lsp" => lsp::run_server().await,
+        // `--stdio` is the conventional flag, which many editors pass by default
+        Some(arg) if arg == "lsp" || arg == "--stdio" => lsp::run_server().await,
         _ => {
-            eprintln!("Usage: gitlab-language-server lsp => run the LSP server using stdin/stdout");
+            eprintln!(
+                "Usage: gitlab-language-server lsp|--stdio => run the LSP server using stdin/stdout"
+            );
         }
     }
 }